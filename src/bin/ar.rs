@@ -0,0 +1,90 @@
+use std::{
+    env::args,
+    fs::{self, File},
+    path::Path,
+    process::exit,
+};
+
+use vsbf::Archive;
+
+fn usage() -> ! {
+    let name = args().next().unwrap();
+    eprintln!("Usage: {name} c <archive> <object> [object...]   create an archive");
+    eprintln!("       {name} t <archive>                        list members");
+    eprintln!("       {name} s <archive>                        list the symbol index");
+    eprintln!("       {name} x <archive> [member...]            extract members");
+    exit(1);
+}
+
+fn fail(what: &str, e: impl std::fmt::Display) -> ! {
+    eprintln!("error: {what}: {e}");
+    exit(1);
+}
+
+fn read_archive(filename: &str) -> Archive {
+    let buf = fs::read(filename).unwrap_or_else(|e| fail(filename, e));
+    Archive::parse(&buf).unwrap_or_else(|e| fail(filename, e))
+}
+
+fn main() {
+    let args: Vec<String> = args().skip(1).collect();
+    let (Some(command), Some(filename)) = (args.first(), args.get(1)) else {
+        usage();
+    };
+    let operands = &args[2..];
+
+    match command.as_str() {
+        "c" => {
+            if operands.is_empty() {
+                usage();
+            }
+
+            let mut ar = Archive::empty();
+            for object in operands {
+                let data = fs::read(object).unwrap_or_else(|e| fail(object, e));
+                // Members are named after the file, so they can be extracted anywhere
+                let name = Path::new(object).file_name().unwrap().to_string_lossy();
+                ar.push_member(&name, data)
+                    .unwrap_or_else(|e| fail(object, e));
+            }
+
+            let written = File::create(filename).and_then(|mut f| ar.write(&mut f));
+            if let Err(e) = written {
+                fail(filename, e);
+            }
+        }
+        "t" => {
+            for member in read_archive(filename).members() {
+                println!("{:<24} {:>8} bytes", member.name, member.data.len());
+            }
+        }
+        "s" => {
+            let ar = read_archive(filename);
+            for sym in ar.symbols() {
+                let member = &ar.members()[sym.member as usize];
+                println!("{:<24} {}", sym.name, member.name);
+            }
+        }
+        "x" => {
+            let ar = read_archive(filename);
+            for name in operands {
+                if !ar.members().iter().any(|m| &m.name == name) {
+                    fail(filename, format!("no member named `{name}`"));
+                }
+            }
+
+            let wanted = ar
+                .members()
+                .iter()
+                .filter(|m| operands.is_empty() || operands.contains(&m.name));
+            for member in wanted {
+                // Don't let a crafted member name escape the current directory
+                let Some(path) = Path::new(&member.name).file_name() else {
+                    fail(filename, format!("invalid member name `{}`", member.name));
+                };
+                fs::write(path, &member.data).unwrap_or_else(|e| fail(&member.name, e));
+            }
+        }
+        _ => usage(),
+    }
+}
@@ -1,158 +1,1261 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     env::args,
     fs::{self, File},
+    io,
+    path::PathBuf,
     process::exit,
 };
 
-use vsbf::{SectionHeader, SegmentHeader, Vsbf};
+use vsbf::{
+    Archive, FileFlags, PermissionFlags, RelocationType, SectionHeader, SectionIndex, SectionType,
+    SegmentHeader, StrTabBuilder, Sym, SymbolBinding, SymbolKind, Vsbf, VsbfError, NO_NAME,
+    SECTION_ABS, SECTION_COMMON,
+};
 
-fn main() {
-    if args().len() < 2 {
-        eprintln!("Usage: {} <filename> [filename...]", args().next().unwrap());
-        exit(1);
+const HELP: &str = "\
+Options:
+  -o <file>                 Write the output to <file> (default: a.out)
+  --entry <symbol>          Start execution at <symbol> (default: _start)
+  --image-base <address>    Place the first section at <address> (default: 0x400000)
+  -L <dir>                  Add <dir> to the library search path
+  -l <name>                 Link against lib<name>.a or lib<name>.o, found in the library search path
+  --map <file>              Write a map of the output layout to <file>
+  --gc-sections             Remove sections that are not reachable from the entry point
+  --keep <symbol>           Keep the section defining <symbol> with --gc-sections
+  -v, --verbose             Print what the linker is doing
+  -h, --help                Print this help";
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: {} [options] <filename> [filename...]",
+        args().next().unwrap()
+    );
+    eprintln!("Try `--help` for more information");
+    exit(1);
+}
+
+/// An input file, as given on the command line
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Input {
+    File(PathBuf),
+    /// A `-l` library, still to be found in the search path
+    Library(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Options {
+    inputs: Vec<Input>,
+    output: PathBuf,
+    entry: Option<String>,
+    image_base: u64,
+    lib_dirs: Vec<PathBuf>,
+    map: Option<PathBuf>,
+    gc_sections: bool,
+    /// Extra roots for `--gc-sections`, besides the entry point
+    keep: Vec<String>,
+    verbose: bool,
+    help: bool,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            inputs: vec![],
+            output: PathBuf::from("a.out"),
+            entry: None,
+            image_base: DEFAULT_IMAGE_BASE,
+            lib_dirs: vec![],
+            map: None,
+            gc_sections: false,
+            keep: vec![],
+            verbose: false,
+            help: false,
+        }
+    }
+}
+
+/// Parse the command line (without the program name)
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut opts = Options::default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("missing argument for `{name}`"))
+        };
+
+        match arg.as_str() {
+            "-o" => opts.output = value("-o")?.into(),
+            "--entry" => opts.entry = Some(value("--entry")?),
+            "--image-base" => {
+                let arg = value("--image-base")?;
+                opts.image_base = match parse_address(&arg) {
+                    Some(addr) if addr % PAGE_SIZE == 0 => addr,
+                    Some(_) => return Err(format!("image base {arg} is not aligned to a page")),
+                    None => return Err(format!("invalid image base `{arg}`")),
+                };
+            }
+            "-L" => opts.lib_dirs.push(value("-L")?.into()),
+            "-l" => opts.inputs.push(Input::Library(value("-l")?)),
+            "--map" => opts.map = Some(value("--map")?.into()),
+            "--gc-sections" => opts.gc_sections = true,
+            "--keep" => opts.keep.push(value("--keep")?),
+            "-v" | "--verbose" => opts.verbose = true,
+            "-h" | "--help" => opts.help = true,
+            _ if arg.len() > 2 && arg.starts_with("-L") => opts.lib_dirs.push(arg[2..].into()),
+            _ if arg.len() > 2 && arg.starts_with("-l") => {
+                opts.inputs.push(Input::Library(arg[2..].to_string()))
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{arg}`")),
+            _ => opts.inputs.push(Input::File(arg.into())),
+        }
+    }
+
+    Ok(opts)
+}
+
+/// Find `lib<name>.a`, or failing that `lib<name>.o`, in the library search path
+fn find_library(lib_dirs: &[PathBuf], name: &str) -> Result<PathBuf, String> {
+    lib_dirs
+        .iter()
+        .flat_map(|dir| ["a", "o"].map(|ext| dir.join(format!("lib{name}.{ext}"))))
+        .find(|path| path.is_file())
+        .ok_or_else(|| format!("cannot find library `-l{name}`"))
+}
+
+/// Names of the symbols an object needs from other objects: those it refers
+/// to without defining them. Weak references don't count.
+fn undefined_names(obj: &Vsbf) -> Result<Vec<&str>, VsbfError> {
+    let mut defined = HashSet::new();
+    let mut needed = vec![];
+
+    for sym in obj.syms() {
+        let name = obj.string_at(sym.name)?;
+        if !sym.is_undefined() {
+            defined.insert(name);
+        } else if sym.is_weak() {
+            // Weak references are satisfied even if nothing defines them
+            defined.insert(name);
+        } else {
+            needed.push(name);
+        }
+    }
+    for rel in obj.rels() {
+        needed.push(obj.string_at(rel.needed)?);
     }
 
-    let filenames: Vec<String> = args().skip(1).collect();
-    let mut files: Vec<Vsbf> = filenames
+    needed.retain(|name| !defined.contains(name));
+    Ok(needed)
+}
+
+/// Add to `objs` the archive members defining symbols that the objects refer
+/// to but don't define, until no more symbols can be resolved this way.
+/// Members are named `archive(member)` in `filenames`.
+fn pull_members(
+    archives: &[(String, Archive)],
+    filenames: &mut Vec<String>,
+    objs: &mut Vec<Vsbf>,
+    verbose: bool,
+) -> Result<(), String> {
+    let mut loaded: Vec<_> = archives
         .iter()
-        .map(|filename| Vsbf::parse(&fs::read(&filename).unwrap()).unwrap().1)
+        .map(|(_, ar)| vec![false; ar.members().len()])
         .collect();
 
+    loop {
+        let mut defined = HashSet::new();
+        let mut needed = BTreeSet::new();
+        for (filename, obj) in filenames.iter().zip(objs.iter()) {
+            let globals = obj
+                .syms()
+                .iter()
+                .filter(|s| !s.is_undefined() && !s.is_local());
+            for sym in globals {
+                defined.insert(
+                    obj.string_at(sym.name)
+                        .map_err(|e| format!("{filename}: {e}"))?,
+                );
+            }
+            needed.extend(undefined_names(obj).map_err(|e| format!("{filename}: {e}"))?);
+        }
+        let needed: Vec<String> = needed
+            .into_iter()
+            .filter(|name| !defined.contains(name))
+            .map(str::to_string)
+            .collect();
+
+        let mut pulled = vec![];
+        for name in &needed {
+            let found = archives.iter().enumerate().find_map(|(i, (_, ar))| {
+                let member = ar.member_defining(name)?;
+                Some((i, member as usize))
+            });
+            let Some((i, member)) = found else {
+                continue;
+            };
+            if loaded[i][member] {
+                continue;
+            }
+            loaded[i][member] = true;
+
+            let (path, ar) = &archives[i];
+            let member = &ar.members()[member];
+            let filename = format!("{path}({})", member.name);
+            if verbose {
+                eprintln!("loading {filename} for `{name}`");
+            }
+            let (_, obj) = Vsbf::parse(&member.data).map_err(|e| format!("{filename}: {e}"))?;
+            pulled.push((filename, obj));
+        }
+
+        if pulled.is_empty() {
+            return Ok(());
+        }
+        for (filename, obj) in pulled {
+            filenames.push(filename);
+            objs.push(obj);
+        }
+    }
+}
+
+fn main() {
+    let opts = match parse_args(args().skip(1)) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("error: {e}");
+            usage();
+        }
+    };
+    if opts.help {
+        println!(
+            "Usage: {} [options] <filename> [filename...]\n\n{HELP}",
+            args().next().unwrap()
+        );
+        return;
+    }
+    if opts.inputs.is_empty() {
+        usage();
+    }
+
+    let mut filenames = vec![];
+    let mut files = vec![];
+    let mut archives = vec![];
+    for input in &opts.inputs {
+        let path = match input {
+            Input::File(path) => path.clone(),
+            Input::Library(name) => find_library(&opts.lib_dirs, name).unwrap_or_else(|e| {
+                eprintln!("error: {e}");
+                exit(1);
+            }),
+        };
+        let filename = path.display().to_string();
+        if opts.verbose {
+            eprintln!("loading {filename}");
+        }
+
+        let buf = fs::read(&path).unwrap_or_else(|e| {
+            eprintln!("error: {filename}: {e}");
+            exit(1);
+        });
+        let parsed = if Archive::is_archive(&buf) {
+            Archive::parse(&buf).map(|ar| archives.push((filename.clone(), ar)))
+        } else {
+            Vsbf::parse(&buf).map(|(_, obj)| {
+                filenames.push(filename.clone());
+                files.push(obj);
+            })
+        };
+        if let Err(e) = parsed {
+            eprintln!("error: {filename}: {e}");
+            exit(1);
+        }
+    }
+
+    if let Err(e) = pull_members(&archives, &mut filenames, &mut files, opts.verbose) {
+        eprintln!("error: {e}");
+        exit(1);
+    }
+
+    let mut valid = true;
+    for (filename, file) in filenames.iter().zip(&files) {
+        for violation in file.validate() {
+            eprintln!("error: {filename}: {violation}");
+            valid = false;
+        }
+    }
+    if !valid {
+        exit(1);
+    }
+
     let mut output = Vsbf::empty();
+    output.set_flags(FileFlags::EXEC);
+
+    let mut symbols = match resolve_symbols(&filenames, &files) {
+        Ok(symbols) => symbols,
+        Err(errors) => {
+            for e in errors {
+                eprintln!("error: {e}");
+            }
+            exit(1);
+        }
+    };
+    if let Err(e) = allocate_commons(&mut symbols, &mut files) {
+        eprintln!("error: {e}");
+        exit(1);
+    }
+
+    let entry_name = opts.entry.as_deref().unwrap_or("_start");
+    // Only the names the output still refers to go in its string table
+    let mut strtab = StrTabBuilder::new();
+    let placements = if opts.gc_sections {
+        let roots = symbols
+            .globals
+            .contains_key(entry_name)
+            .then_some(entry_name);
+        let roots: Vec<_> = roots
+            .into_iter()
+            .chain(opts.keep.iter().map(|s| s.as_str()))
+            .collect();
+
+        let live = match live_sections(&files, &symbols, &roots) {
+            Ok(live) => live,
+            Err(e) => {
+                eprintln!("error: {e}");
+                exit(1);
+            }
+        };
+        report_gc(&filenames, &files, &live, opts.verbose);
+        merge_live_sections(&mut output, &mut files, &live, &mut strtab)
+    } else {
+        merge_sections(&mut output, &mut files, &mut strtab)
+    };
+    let placements = placements.unwrap_or_else(|e| {
+        eprintln!("error: {e}");
+        exit(1);
+    });
+    if let Err(e) = layout_sections(&mut output, opts.image_base) {
+        eprintln!("error: {e}");
+        exit(1);
+    }
+    if let Err(e) = relocate(&mut output, &files, &placements, &symbols) {
+        eprintln!("error: {e}");
+        exit(1);
+    }
+
+    match symbols.globals.get(entry_name) {
+        Some(def) => output.set_entry(symbol_address(&output.sections(), &placements, def)),
+        None if opts.entry.is_some() => {
+            eprintln!("error: entry symbol `{entry_name}` is not defined");
+            exit(1);
+        }
+        None => {
+            let sections = output.sections();
+            let text = sections
+                .iter()
+                .find(|s| s.is_text())
+                .map_or(0, |s| s.memory);
+            eprintln!("warning: no `_start` symbol, defaulting entry point to 0x{text:x}");
+            output.set_entry(text);
+        }
+    }
 
-    // TODO: check that there are no relocations referring to a name without a symbol defined (aka, check for undefined symbols)
-    // TODO: check that every relocation is in bounds of a single section
-    output.set_strtab(merge_strtabs(&mut files));
-    merge_sections(&mut output, &mut files);
+    emit_symbols(&mut output, &symbols, &placements, &mut strtab);
+    output.set_strtab(strtab.finish());
     allocate_segments(&mut output);
 
-    let mut outfile = File::create("test").unwrap();
-    output.write(&mut outfile).unwrap();
+    if opts.verbose {
+        for sec in output.sections() {
+            eprintln!(
+                "{} at 0x{:x}, 0x{:x} bytes",
+                section_name(&output, &sec),
+                sec.memory,
+                sec.size_in_memory()
+            );
+        }
+        eprintln!("entry point at 0x{:x}", output.entry());
+    }
+
+    if let Some(map) = &opts.map {
+        let written = File::create(map).and_then(|mut f| {
+            write_map(&mut f, &output, &filenames, &files, &placements, &symbols)
+        });
+        if let Err(e) = written {
+            eprintln!("error: {}: {e}", map.display());
+            exit(1);
+        }
+    }
+
+    let output_name = opts.output.display();
+    let written = File::create(&opts.output).and_then(|mut f| output.write(&mut f));
+    if let Err(e) = written {
+        eprintln!("error: {output_name}: {e}");
+        exit(1);
+    }
+    if opts.verbose {
+        eprintln!("wrote {output_name}");
+    }
+}
+
+/// Size of a page on the target: segments with different permissions never share one
+const PAGE_SIZE: u64 = 0x1000;
+/// Address the first section is placed at, unless `--image-base` says otherwise
+const DEFAULT_IMAGE_BASE: u64 = 0x400000;
+
+/// Parse a decimal or `0x`-prefixed hexadecimal address
+fn parse_address(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// A symbol definition found in one of the input objects
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Definition {
+    /// Index of the object defining the symbol
+    obj: usize,
+    /// Index of the section containing the symbol, in the defining object,
+    /// or one of the reserved section indices
+    section: u16,
+    /// Offset of the symbol from the start of its section
+    value: u64,
+    size: u32,
+    binding: SymbolBinding,
+    kind: SymbolKind,
+}
+impl Definition {
+    /// Which definition wins when a symbol is defined more than once: weak
+    /// definitions lose to common ones, which lose to global ones
+    fn strength(&self) -> u8 {
+        match (self.binding, self.section) {
+            (SymbolBinding::Weak, _) => 0,
+            (_, SECTION_COMMON) => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// Every symbol definition, as seen from each object
+#[derive(Clone, Debug, Default)]
+struct SymbolTable {
+    /// Global and weak symbols, visible from every object
+    globals: HashMap<String, Definition>,
+    /// Local symbols of each object
+    locals: Vec<HashMap<String, Definition>>,
+}
+impl SymbolTable {
+    /// Find the definition a reference to `name` from object `obj` resolves to
+    fn lookup(&self, obj: usize, name: &str) -> Option<&Definition> {
+        self.locals[obj]
+            .get(name)
+            .or_else(|| self.globals.get(name))
+    }
+}
+
+/// Build the symbol table, checking that no symbol has two global definitions
+/// and that every relocation refers to a defined symbol.
+///
+/// On failure, every problem found is returned, not just the first one.
+fn resolve_symbols(filenames: &[String], objs: &[Vsbf]) -> Result<SymbolTable, Vec<String>> {
+    let mut symbols = SymbolTable::default();
+    let mut errors = vec![];
+
+    let location = |def: &Definition| {
+        format!(
+            "{} (section {}, offset 0x{:x})",
+            filenames[def.obj],
+            SectionIndex(def.section),
+            def.value
+        )
+    };
+    let mut weak_refs = vec![];
+
+    for (i, obj) in objs.iter().enumerate() {
+        let mut locals = HashMap::new();
+
+        for sym in obj.syms() {
+            if sym.kind == SymbolKind::File {
+                continue;
+            }
+
+            let name = match obj.string_at(sym.name) {
+                Ok(name) => name,
+                Err(e) => {
+                    errors.push(format!("{}: {e}", filenames[i]));
+                    continue;
+                }
+            };
+            let def = Definition {
+                obj: i,
+                section: sym.section,
+                value: sym.value,
+                size: sym.size,
+                binding: sym.binding,
+                kind: sym.kind,
+            };
+
+            if sym.is_undefined() {
+                // Unlike global ones, weak references don't need a definition
+                if sym.is_weak() {
+                    weak_refs.push((i, name));
+                }
+                continue;
+            }
+
+            if sym.is_local() {
+                if sym.is_common() {
+                    errors.push(format!(
+                        "{}: local symbol `{name}` can't be common",
+                        filenames[i]
+                    ));
+                } else {
+                    locals.entry(name.to_string()).or_insert(def);
+                }
+                continue;
+            }
+
+            let Some(prev) = symbols.globals.get_mut(name) else {
+                symbols.globals.insert(name.to_string(), def);
+                continue;
+            };
+            match (prev.strength(), def.strength()) {
+                (a, b) if a < b => *prev = def,
+                (a, b) if a > b => {}
+                // Two weak definitions: keep the first one
+                (0, _) => {}
+                // Two common definitions: allocate enough space for both
+                (1, _) => {
+                    prev.size = prev.size.max(def.size);
+                    prev.value = prev.value.max(def.value);
+                }
+                _ => errors.push(format!(
+                    "duplicate symbol `{name}`: defined in {} and in {}",
+                    location(prev),
+                    location(&def),
+                )),
+            }
+        }
+
+        symbols.locals.push(locals);
+    }
+
+    // Undefined weak symbols resolve to 0
+    for (obj, name) in weak_refs {
+        symbols
+            .globals
+            .entry(name.to_string())
+            .or_insert(Definition {
+                obj,
+                section: SECTION_ABS,
+                value: 0,
+                size: 0,
+                binding: SymbolBinding::Weak,
+                kind: SymbolKind::None,
+            });
+    }
+
+    for (i, obj) in objs.iter().enumerate() {
+        for rel in obj.rels() {
+            let name = match obj.string_at(rel.needed) {
+                Ok(name) => name,
+                Err(e) => {
+                    errors.push(format!("{}: {e}", filenames[i]));
+                    continue;
+                }
+            };
+            if symbols.lookup(i, name).is_none() {
+                errors.push(format!(
+                    "undefined symbol `{name}`, referenced by {} at offset 0x{:x}",
+                    filenames[i], rel.offset,
+                ));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(symbols)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Allocate space for the common symbols in a new object, and point their
+/// definitions to it
+fn allocate_commons(symbols: &mut SymbolTable, objs: &mut Vec<Vsbf>) -> Result<(), String> {
+    let mut commons: Vec<_> = symbols
+        .globals
+        .iter_mut()
+        .filter(|(_, def)| def.section == SECTION_COMMON)
+        .collect();
+    if commons.is_empty() {
+        return Ok(());
+    }
+    commons.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+    let obj = objs.len();
+    let mut size = 0u64;
+    for (_, def) in commons {
+        size = size.next_multiple_of(def.value.max(1));
+        def.obj = obj;
+        def.section = 0;
+        def.value = size;
+        size += def.size as u64;
+    }
+
+    let Ok(file_size) = u32::try_from(size) else {
+        return Err(format!(
+            "common symbols need 0x{size:x} bytes, more than a section can hold"
+        ));
+    };
+
+    let mut common = Vsbf::empty();
+    common.push_section(SectionHeader {
+        name: NO_NAME,
+        typ: SectionType::Bss,
+        flags: PermissionFlags::R | PermissionFlags::W,
+        file_size,
+        offset: 0,
+        memory: 0,
+    });
+
+    objs.push(common);
+    symbols.locals.push(HashMap::new());
+    Ok(())
+}
+
+/// Give the output a symbol table, with every symbol that made it into the
+/// output, so that it can be looked up by name or address. Names are added to
+/// `strtab`, which becomes the output's string table.
+fn emit_symbols(
+    out: &mut Vsbf,
+    symbols: &SymbolTable,
+    placements: &[Vec<Option<Placement>>],
+    strtab: &mut StrTabBuilder,
+) {
+    let mut globals: Vec<_> = symbols.globals.iter().collect();
+    globals.sort_unstable_by_key(|(name, _)| *name);
+    let mut locals: Vec<_> = symbols
+        .locals
+        .iter()
+        .flat_map(|locals| {
+            let mut locals: Vec<_> = locals.iter().collect();
+            locals.sort_unstable_by_key(|(name, _)| *name);
+            locals
+        })
+        .collect();
+    // Local symbols come first, like in ELF
+    locals.extend(globals);
+
+    for (name, def) in locals {
+        if is_discarded(placements, def) {
+            continue;
+        }
+
+        let (section, value) = match def.section {
+            SECTION_ABS => (SECTION_ABS, def.value),
+            section => {
+                let placement = placements[def.obj][section as usize].unwrap();
+                (
+                    placement.section as u16,
+                    placement.offset as u64 + def.value,
+                )
+            }
+        };
+        out.push_sym(Sym {
+            name: strtab.add(name),
+            size: def.size,
+            section,
+            value,
+            binding: def.binding,
+            kind: def.kind,
+        });
+    }
+}
+
+/// Name of a section. Input files have been validated, so it can be read.
+fn section_name<'a>(obj: &'a Vsbf, sec: &SectionHeader) -> &'a str {
+    obj.section_name(sec)
+        .unwrap_or_else(|_| sec.typ.default_name())
+}
+
+/// Where an input section ended up in the output file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Placement {
+    /// Index of the output section the input section was merged into
+    section: usize,
+    /// Offset of the input section from the start of the output section
+    offset: u32,
 }
 
-fn merge_strtabs(objs: &mut [Vsbf]) -> Vec<u8> {
-    let mut new_strtab = vec![];
-    let mut dupes = vec![];
+/// Output sections that `--gc-sections` never removes: nothing refers to
+/// constructor and destructor tables, they are found by the C runtime
+const KEPT_SECTIONS: &[&str] = &[".init_array", ".fini_array"];
+
+/// Find the input sections reachable from the sections defining the `roots`
+/// symbols and from the [`KEPT_SECTIONS`], following relocations. Returns, for
+/// each object, whether each of its sections is live.
+///
+/// Must run after `allocate_commons`, so that common symbols have a section.
+fn live_sections(
+    objs: &[Vsbf],
+    symbols: &SymbolTable,
+    roots: &[&str],
+) -> Result<Vec<Vec<bool>>, String> {
+    if roots.is_empty() {
+        return Err("`--gc-sections` needs an entry point or `--keep` symbols".to_string());
+    }
+
+    let mut live: Vec<_> = objs
+        .iter()
+        .map(|obj| vec![false; obj.sections().len()])
+        .collect();
+    let mut queue = vec![];
+    let mut mark = |obj: usize, section: usize, queue: &mut Vec<(usize, usize)>| {
+        if let Some(live @ false) = live[obj].get_mut(section) {
+            *live = true;
+            queue.push((obj, section));
+        }
+    };
 
+    for &root in roots {
+        let Some(def) = symbols.globals.get(root) else {
+            return Err(format!("cannot keep undefined symbol `{root}`"));
+        };
+        mark(def.obj, def.section as usize, &mut queue);
+    }
     for (i, obj) in objs.iter().enumerate() {
-        for (j, str) in obj.strings() {
-            let off = new_strtab.len() as u32;
+        for (j, sec) in obj.sections().iter().enumerate() {
+            if KEPT_SECTIONS.contains(&output_section(section_name(obj, sec)).1) {
+                mark(i, j, &mut queue);
+            }
+        }
+    }
 
-            let len: u16 = str.len().try_into().unwrap();
-            new_strtab.extend_from_slice(&len.to_le_bytes());
-            new_strtab.extend_from_slice(str.as_bytes());
+    while let Some((i, section)) = queue.pop() {
+        let obj = &objs[i];
+        let sec = obj.sections()[section];
+        let start = sec.offset as u64;
+        let end = start + sec.size_in_file() as u64;
 
-            dupes.push((off, i, j));
+        for rel in obj.rels() {
+            if !(start..end).contains(&rel.offset) {
+                continue;
+            }
+            let name = obj.string_at(rel.needed).map_err(|e| e.to_string())?;
+            if let Some(def) = symbols.lookup(i, name) {
+                mark(def.obj, def.section as usize, &mut queue);
+            }
         }
     }
 
-    // Adjust symbols
-    for &(off, i, j) in &dupes {
-        for sym in objs[i].syms_mut() {
-            if sym.name == j {
-                sym.name = off;
+    Ok(live)
+}
+
+/// Tell the user which sections `--gc-sections` removed
+fn report_gc(filenames: &[String], objs: &[Vsbf], live: &[Vec<bool>], verbose: bool) {
+    let mut count = 0;
+    let mut size = 0;
+
+    for (i, (obj, obj_live)) in objs.iter().zip(live).enumerate() {
+        let filename = filenames.get(i).map_or("<common>", |f| f.as_str());
+        for (j, sec) in obj.sections().iter().enumerate() {
+            if obj_live[j] {
+                continue;
             }
+            if verbose {
+                eprintln!(
+                    "removing unused section {j} ({}, 0x{:x} bytes) of {filename}",
+                    section_name(obj, sec),
+                    sec.size_in_memory()
+                );
+            }
+            count += 1;
+            size += sec.size_in_memory();
         }
     }
 
-    // TODO: Adjust relocations
+    eprintln!("removed {count} unused sections (0x{size:x} bytes)");
+}
 
-    new_strtab
+/// Merge the sections of every object into output sections (see
+/// [`OUTPUT_SECTIONS`]), returning, for each object, where each of its
+/// sections was placed. Each input section is aligned to
+/// [`section_alignment`] within its output section. Fails if an output section
+/// gets too large.
+///
+/// The names of the output sections are added to `strtab`, which is to become
+/// the output's string table.
+fn merge_sections(
+    out: &mut Vsbf,
+    objs: &mut [Vsbf],
+    strtab: &mut StrTabBuilder,
+) -> Result<Vec<Vec<Option<Placement>>>, String> {
+    let live: Vec<_> = objs
+        .iter()
+        .map(|obj| vec![true; obj.sections().len()])
+        .collect();
+    merge_live_sections(out, objs, &live, strtab)
 }
 
-fn merge_sections(out: &mut Vsbf, objs: &mut [Vsbf]) {
-    let mut merged = HashMap::<_, (SectionHeader, Vec<u8>)>::new();
+/// Like [`merge_sections`], but leave out the sections that aren't `live`,
+/// which get no placement.
+fn merge_live_sections(
+    out: &mut Vsbf,
+    objs: &mut [Vsbf],
+    live: &[Vec<bool>],
+    strtab: &mut StrTabBuilder,
+) -> Result<Vec<Vec<Option<Placement>>>, String> {
+    // Output sections are identified by name and type, and keep the order
+    // of their first input section until sorted below
+    let mut index = HashMap::new();
+    let mut merged: Vec<(usize, &str, SectionHeader, Vec<u8>)> = vec![];
+    let mut offsets = vec![];
+
+    for (obj, obj_live) in objs.iter().zip(live) {
+        let mut obj_offsets = vec![];
+
+        for (sec, &live) in obj.sections().into_iter().zip(obj_live) {
+            if !live {
+                obj_offsets.push(None);
+                continue;
+            }
 
-    for obj in objs {
-        for sec in obj.sections() {
             let start = sec.offset as usize;
-            let end = start + sec.file_size as usize;
+            let end = start + sec.size_in_file() as usize;
+            let (rule, name) = output_section(section_name(obj, &sec));
+
+            if let Some(&i) = index.get(&(name, sec.typ)) {
+                let (_, _, x, data): &mut (_, _, SectionHeader, Vec<u8>) = &mut merged[i];
+                let too_large = || format!("output section `{name}` is larger than 4 GiB");
 
-            if let Some((x, data)) = merged.get_mut(&sec.typ) {
-                x.file_size += sec.file_size;
+                // Every input section starts at its own alignment in the output section
+                let offset = x
+                    .file_size
+                    .checked_next_multiple_of(section_alignment(&sec) as u32)
+                    .ok_or_else(too_large)?;
+                if !x.is_bss() {
+                    data.resize(offset as usize, 0);
+                }
+
+                obj_offsets.push(Some((i, offset)));
+                x.file_size = offset.checked_add(sec.file_size).ok_or_else(too_large)?;
                 x.flags |= sec.flags;
                 data.extend_from_slice(&obj.data()[start..end]);
             } else {
-                merged.insert(sec.typ, (sec, obj.data()[start..end].to_vec()));
+                index.insert((name, sec.typ), merged.len());
+                obj_offsets.push(Some((merged.len(), 0)));
+                merged.push((rule, name, sec, obj.data()[start..end].to_vec()));
             }
         }
+
+        offsets.push(obj_offsets);
     }
 
-    let mut merged: Vec<_> = merged.into_values().collect();
-    merged.sort_unstable_by_key(|s| s.0.typ as u8);
-    for (mut sec, data) in merged {
-        sec.offset = out.data().len() as u32;
+    // Keep sections of the same type together, in the order of `OUTPUT_SECTIONS`
+    let mut order: Vec<_> = (0..merged.len()).collect();
+    order.sort_by_key(|&i| {
+        let (rule, name, sec, _) = &merged[i];
+        (sec.typ as u8, *rule, *name)
+    });
+    let mut position = vec![0; merged.len()];
+    for (pos, &i) in order.iter().enumerate() {
+        position[i] = pos;
+        let (_, name, mut sec, ref data) = merged[i];
+        sec.name = strtab.add(name);
+        sec.offset = u32::try_from(out.data().len())
+            .map_err(|_| format!("output section `{name}` starts past 4 GiB in the file"))?;
+        sec.memory = sec.offset as u64;
         out.push_section(sec);
-        out.data_mut().extend_from_slice(&data);
+        out.data_mut().extend_from_slice(data);
+    }
+
+    let placements = offsets
+        .into_iter()
+        .map(|obj_offsets| {
+            obj_offsets
+                .into_iter()
+                .map(|placement| {
+                    placement.map(|(i, offset)| Placement {
+                        section: position[i],
+                        offset,
+                    })
+                })
+                .collect()
+        })
+        .collect();
+    Ok(placements)
+}
+
+/// How input sections are grouped into output sections, in output order. An
+/// input section goes to the first output section with a pattern matching its
+/// name, where a trailing `*` matches anything.
+const OUTPUT_SECTIONS: &[(&str, &[&str])] = &[
+    (".text", &[".text", ".text.*"]),
+    (".data", &[".data", ".data.*"]),
+    (".init_array", &[".init_array", ".init_array.*"]),
+    (".fini_array", &[".fini_array", ".fini_array.*"]),
+    (".rodata", &[".rodata", ".rodata.*"]),
+    (".bss", &[".bss", ".bss.*"]),
+];
+
+/// Name of the output section for an input section named `name`, with its
+/// position in [`OUTPUT_SECTIONS`]. Sections matching no pattern keep their
+/// name, and go after the others.
+fn output_section(name: &str) -> (usize, &str) {
+    let matches = |pattern: &str| match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    };
+
+    OUTPUT_SECTIONS
+        .iter()
+        .position(|(_, patterns)| patterns.iter().any(|p| matches(p)))
+        .map_or((OUTPUT_SECTIONS.len(), name), |i| (i, OUTPUT_SECTIONS[i].0))
+}
+
+/// Minimum alignment of an output section in memory
+fn section_alignment(sec: &SectionHeader) -> u64 {
+    match sec.typ {
+        SectionType::Text => 16,
+        SectionType::Data | SectionType::Rodata | SectionType::Bss => 8,
+    }
+}
+
+/// Give every output section its virtual address, starting at `image_base`.
+///
+/// Sections are laid out in order, each aligned to [`section_alignment`], and
+/// a section whose permissions differ from the previous one starts on a new
+/// page so that it can be mapped separately. Fails if the sections don't fit
+/// in the address space.
+fn layout_sections(out: &mut Vsbf, image_base: u64) -> Result<(), String> {
+    let mut addr = image_base;
+    let mut prev_flags = None;
+
+    for sec in out.sections_mut() {
+        let page_break = prev_flags.is_some_and(|flags| flags != sec.flags);
+        let align = if page_break { PAGE_SIZE } else { 1 };
+
+        let start = addr
+            .checked_next_multiple_of(align)
+            .and_then(|addr| addr.checked_next_multiple_of(section_alignment(sec)));
+        let end = start.and_then(|start| start.checked_add(sec.size_in_memory() as u64));
+        let (Some(start), Some(end)) = (start, end) else {
+            return Err("sections don't fit above the image base".to_string());
+        };
+
+        sec.memory = start;
+        addr = end;
+        prev_flags = Some(sec.flags);
     }
+
+    Ok(())
+}
+
+/// Whether a symbol is defined in a section removed by `--gc-sections`
+fn is_discarded(placements: &[Vec<Option<Placement>>], def: &Definition) -> bool {
+    def.section != SECTION_ABS && placements[def.obj][def.section as usize].is_none()
+}
+
+/// Final address of a symbol, once sections have been merged. The symbol must
+/// not be in a discarded section.
+fn symbol_address(
+    out_sections: &[SectionHeader],
+    placements: &[Vec<Option<Placement>>],
+    def: &Definition,
+) -> u64 {
+    if def.section == SECTION_ABS {
+        return def.value;
+    }
+
+    let placement = placements[def.obj][def.section as usize].expect("symbol was discarded");
+    out_sections[placement.section].memory + placement.offset as u64 + def.value
+}
+
+/// Patch every relocation of every object into the merged output data.
+///
+/// Must run after `layout_sections`, which gives every section its final address.
+fn relocate(
+    out: &mut Vsbf,
+    objs: &[Vsbf],
+    placements: &[Vec<Option<Placement>>],
+    symbols: &SymbolTable,
+) -> Result<(), String> {
+    let out_sections = out.sections();
+
+    for (i, (obj, obj_placements)) in objs.iter().zip(placements).enumerate() {
+        let sections = obj.sections();
+
+        for rel in obj.rels() {
+            let name = obj.string_at(rel.needed).map_err(|e| e.to_string())?;
+            let Some(def) = symbols.lookup(i, name) else {
+                return Err(format!("undefined symbol `{name}`"));
+            };
+
+            let Some(idx) = sections.iter().position(|sec| {
+                let start = sec.offset as u64;
+                let end = start + sec.size_in_file() as u64;
+                (start..end).contains(&rel.offset)
+            }) else {
+                return Err(format!(
+                    "relocation against `{name}` at 0x{:x} is outside of any section",
+                    rel.offset
+                ));
+            };
+
+            // Relocations in removed sections have nothing to patch
+            let Some(placement) = obj_placements[idx] else {
+                continue;
+            };
+            let sym = symbol_address(&out_sections, placements, def);
+            let out_sec = out_sections[placement.section];
+            let delta = rel.offset - sections[idx].offset as u64 + placement.offset as u64;
+            let place = out_sec.offset as usize + delta as usize;
+            let Some(pc) = out_sec.memory.checked_add(delta) else {
+                return Err(format!(
+                    "relocation against `{name}` is past the end of the address space"
+                ));
+            };
+            let target = match rel.typ {
+                // There is no PLT in a statically linked file: calls go straight to the symbol
+                RelocationType::Abs64
+                | RelocationType::Abs32
+                | RelocationType::Abs32S
+                | RelocationType::Pc32
+                | RelocationType::Plt32 => sym,
+                RelocationType::GotPcRel => {
+                    return Err(format!(
+                    "relocation against `{name}` at 0x{pc:x} needs a GOT, which is not supported"
+                ))
+                }
+            };
+
+            let mut value = target as i128 + rel.addend as i128;
+            if rel.typ.is_pc_relative() {
+                value -= pc as i128;
+            }
+            let Some(bytes) = rel.typ.encode(value) else {
+                return Err(format!(
+                    "{} relocation against `{name}` at 0x{pc:x} overflows",
+                    rel.typ
+                ));
+            };
+
+            let Some(dst) = out.data_mut().get_mut(place..place + bytes.len()) else {
+                return Err(format!(
+                    "relocation against `{name}` at 0x{pc:x} extends past the end of the file"
+                ));
+            };
+            dst.copy_from_slice(&bytes);
+        }
+    }
+
+    Ok(())
 }
 
+/// Create the segments covering every section. Sections that follow each
+/// other both in the file and in memory, with the same permissions, share a
+/// segment.
 fn allocate_segments(obj: &mut Vsbf) {
+    let mut segments: Vec<SegmentHeader> = vec![];
+
     for sec in obj.sections() {
-        obj.push_segment(SegmentHeader {
+        if let Some(seg) = segments.last_mut() {
+            let contiguous = seg.flags == sec.flags
+                && seg.file_size == seg.mem_size
+                && seg.file + seg.file_size == sec.offset
+                && seg.mem + seg.mem_size as u64 == sec.memory;
+            if contiguous {
+                seg.file_size += sec.size_in_file();
+                seg.mem_size += sec.size_in_memory();
+                continue;
+            }
+        }
+
+        segments.push(SegmentHeader {
             typ: 0,
             flags: sec.flags,
-            align: 0x1000,
+            align: PAGE_SIZE as u16,
             file: sec.offset,
-            mem: sec.offset as _,
-            file_size: sec.file_size as _,
-            mem_size: sec.file_size as _,
+            mem: sec.memory,
+            file_size: sec.size_in_file(),
+            mem_size: sec.size_in_memory(),
         });
     }
+
+    for seg in segments {
+        obj.push_segment(seg);
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use vsbf::{PermissionFlags, SectionType, Sym};
+/// Write a human-readable description of the output layout: segments,
+/// sections with the contribution of every input object, and symbols sorted by
+/// address.
+fn write_map(
+    w: &mut dyn io::Write,
+    out: &Vsbf,
+    filenames: &[String],
+    objs: &[Vsbf],
+    placements: &[Vec<Option<Placement>>],
+    symbols: &SymbolTable,
+) -> io::Result<()> {
+    // Objects past the ones given on the command line are synthesized by the linker
+    let filename = |obj: usize| filenames.get(obj).map_or("<common>", |f| f.as_str());
+    let out_sections = out.sections();
 
-    use super::*;
+    writeln!(w, "Segments:")?;
+    writeln!(
+        w,
+        "  {:<18} {:<10} {:<10} Flags",
+        "Address", "MemSize", "FileSize"
+    )?;
+    for seg in out.segments() {
+        writeln!(
+            w,
+            "  0x{:016x} 0x{:08x} 0x{:08x} {}",
+            seg.mem, seg.mem_size, seg.file_size, seg.flags
+        )?;
+    }
 
-    #[test]
-    fn test_merge_strtab() {
-        let mut v1 = Vsbf::empty();
-        let mut v2 = Vsbf::empty();
+    writeln!(w, "\nSections:")?;
+    writeln!(w, "  {:<12} {:<18} {:<10} Flags", "Name", "Address", "Size")?;
+    for (i, sec) in out_sections.iter().enumerate() {
+        writeln!(
+            w,
+            "  {:<12} 0x{:016x} 0x{:08x} {}",
+            section_name(out, sec),
+            sec.memory,
+            sec.size_in_memory(),
+            sec.flags
+        )?;
 
-        v1.push_string("hello");
-        v1.push_sym(Sym {
-            name: 0,
-            size: 0,
-            section: 0,
-            value: 0,
-        });
+        for (obj, obj_placements) in placements.iter().enumerate() {
+            let obj_sections = objs[obj].sections();
+            for (j, placement) in obj_placements.iter().enumerate() {
+                let Some(placement) = placement.filter(|p| p.section == i) else {
+                    continue;
+                };
+                writeln!(
+                    w,
+                    "    0x{:016x} 0x{:08x} {} (section {j}, offset 0x{:x})",
+                    sec.memory + placement.offset as u64,
+                    obj_sections[j].size_in_memory(),
+                    filename(obj),
+                    placement.offset
+                )?;
+            }
+        }
+    }
 
-        v2.push_string("Hi");
-        v2.push_sym(Sym {
-            name: 0,
-            size: 0,
-            section: 0,
-            value: 0,
-        });
+    let mut syms: Vec<_> = symbols
+        .globals
+        .iter()
+        .chain(symbols.locals.iter().flatten())
+        .filter(|(_, def)| !is_discarded(placements, def))
+        .map(|(name, def)| {
+            let addr = symbol_address(&out_sections, placements, def);
+            (addr, name, def)
+        })
+        .collect();
+    syms.sort_unstable_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
 
-        let mut objs = [v1, v2];
-        let new_strtab = merge_strtabs(&mut objs);
-        let [v1, v2] = objs;
+    let discarded: Vec<_> = placements
+        .iter()
+        .enumerate()
+        .flat_map(|(obj, p)| p.iter().enumerate().map(move |(j, p)| (obj, j, p)))
+        .filter(|(_, _, placement)| placement.is_none())
+        .collect();
+    if !discarded.is_empty() {
+        writeln!(w, "\nDiscarded sections:")?;
+        for (obj, j, _) in discarded {
+            let sec = objs[obj].sections()[j];
+            writeln!(
+                w,
+                "  {:<12} 0x{:08x} {} (section {j})",
+                section_name(&objs[obj], &sec),
+                sec.size_in_memory(),
+                filename(obj)
+            )?;
+        }
+    }
 
-        assert_eq!(new_strtab, b"\x05\x00hello\x02\x00Hi");
-        assert_eq!(v1.syms()[0].name, 0);
-        assert_eq!(v2.syms()[0].name, 7);
+    writeln!(w, "\nSymbols:")?;
+    writeln!(w, "  {:<18} {:<6} {:<6} Name", "Address", "Size", "Bind")?;
+    for (addr, name, def) in syms {
+        writeln!(
+            w,
+            "  0x{addr:016x} 0x{:04x} {:<6} {name} ({})",
+            def.size,
+            def.binding,
+            filename(def.obj)
+        )?;
     }
 
-    #[test]
-    fn test_merge_sections() {
-        let mut v1 = Vsbf::empty();
-        let mut v2 = Vsbf::empty();
-        let mut out = Vsbf::empty();
+    Ok(())
+}
 
-        v1.push_section(SectionHeader {
-            typ: SectionType::Text,
-            flags: PermissionFlags::R | PermissionFlags::X,
-            file_size: 10,
+#[cfg(test)]
+mod tests {
+    use vsbf::{Rel, SECTION_UNDEF};
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let opts = args(&[
+            "-o", "out", "-v", "a.o", "-Llibs", "-lc", "--entry", "main", "-L", "more", "-l", "m",
+            "b.o",
+        ])
+        .unwrap();
+        assert_eq!(
+            opts,
+            Options {
+                inputs: vec![
+                    Input::File("a.o".into()),
+                    Input::Library("c".into()),
+                    Input::Library("m".into()),
+                    Input::File("b.o".into()),
+                ],
+                output: "out".into(),
+                entry: Some("main".into()),
+                lib_dirs: vec!["libs".into(), "more".into()],
+                verbose: true,
+                ..Options::default()
+            }
+        );
+
+        let opts = args(&["--image-base", "0x10000", "--map", "out.map", "a.o"]).unwrap();
+        assert_eq!(opts.image_base, 0x10000);
+        assert_eq!(opts.map, Some("out.map".into()));
+        assert_eq!(opts.output, PathBuf::from("a.out"));
+
+        assert!(args(&["--image-base", "0x10010"]).is_err());
+        assert!(args(&["--image-base", "base"]).is_err());
+        assert!(args(&["a.o", "-o"]).is_err());
+        assert!(args(&["--bogus"]).is_err());
+    }
+
+    #[test]
+    fn test_merge_sections() {
+        let mut v1 = Vsbf::empty();
+        let mut v2 = Vsbf::empty();
+        let mut out = Vsbf::empty();
+
+        v1.push_section(SectionHeader {
+            name: NO_NAME,
+            typ: SectionType::Text,
+            flags: PermissionFlags::R | PermissionFlags::X,
+            file_size: 10,
             offset: 0,
             memory: 0,
         });
         v1.data_mut().extend_from_slice(b"aaaaabbbbb");
         v1.push_section(SectionHeader {
+            name: NO_NAME,
             typ: SectionType::Rodata,
             flags: PermissionFlags::R,
             file_size: 10,
@@ -161,6 +1264,7 @@ mod tests {
         });
         v1.data_mut().extend_from_slice(b"cccccddddd");
         v1.push_section(SectionHeader {
+            name: NO_NAME,
             typ: SectionType::Rodata,
             flags: PermissionFlags::R,
             file_size: 10,
@@ -170,6 +1274,7 @@ mod tests {
         v1.data_mut().extend_from_slice(b"eeeeefffff");
 
         v2.push_section(SectionHeader {
+            name: NO_NAME,
             typ: SectionType::Text,
             flags: PermissionFlags::R | PermissionFlags::X,
             file_size: 20,
@@ -178,6 +1283,7 @@ mod tests {
         });
         v2.data_mut().extend_from_slice(b"ggggghhhhhiiiiijjjjj");
         v2.push_section(SectionHeader {
+            name: NO_NAME,
             typ: SectionType::Data,
             flags: PermissionFlags::R | PermissionFlags::W,
             file_size: 10,
@@ -187,25 +1293,972 @@ mod tests {
         v2.data_mut().extend_from_slice(b"kkkkklllll");
 
         let mut objs = [v1, v2];
-        merge_sections(&mut out, &mut objs);
+        merge_sections(&mut out, &mut objs, &mut StrTabBuilder::new()).unwrap();
 
         assert!(out.sections()[0].is_text());
         assert!(out.sections()[0].is_rx());
         assert_eq!(out.sections()[0].offset, 0);
-        assert_eq!(out.sections()[0].file_size, 30);
-        assert_eq!(&out.data()[0..30], b"aaaaabbbbbggggghhhhhiiiiijjjjj");
+        assert_eq!(out.sections()[0].file_size, 36);
+        assert_eq!(&out.data()[0..10], b"aaaaabbbbb");
+        assert_eq!(&out.data()[10..16], &[0; 6]);
+        assert_eq!(&out.data()[16..36], b"ggggghhhhhiiiiijjjjj");
 
         assert!(out.sections()[1].is_data());
         assert!(out.sections()[1].is_rw());
-        assert_eq!(out.sections()[1].offset, 30);
+        assert_eq!(out.sections()[1].offset, 36);
         assert_eq!(out.sections()[1].file_size, 10);
-        assert_eq!(&out.data()[30..40], b"kkkkklllll");
+        assert_eq!(&out.data()[36..46], b"kkkkklllll");
 
-        println!("{:?}", out.sections()[2]);
         assert!(out.sections()[2].is_rodata());
         assert!(out.sections()[2].is_ronly());
-        assert_eq!(out.sections()[2].offset, 40);
-        assert_eq!(out.sections()[2].file_size, 20);
-        assert_eq!(&out.data()[40..60], b"cccccdddddeeeeefffff");
+        assert_eq!(out.sections()[2].offset, 46);
+        assert_eq!(out.sections()[2].file_size, 26);
+        assert_eq!(&out.data()[46..56], b"cccccddddd");
+        assert_eq!(&out.data()[62..72], b"eeeeefffff");
+    }
+
+    #[test]
+    fn test_relocate() {
+        let mut v1 = Vsbf::empty();
+        let mut v2 = Vsbf::empty();
+        let mut out = Vsbf::empty();
+
+        // v1: `call foo` followed by a pointer to `bar`
+        v1.push_string("foo");
+        v1.push_string("bar");
+        v1.push_section(SectionHeader {
+            name: NO_NAME,
+            typ: SectionType::Text,
+            flags: PermissionFlags::R | PermissionFlags::X,
+            file_size: 13,
+            offset: 0,
+            memory: 0,
+        });
+        v1.data_mut()
+            .extend_from_slice(&[0xe8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        v1.push_rel(Rel {
+            typ: RelocationType::Pc32,
+            addend: -4,
+            needed: 0,
+            offset: 1,
+        });
+        v1.push_rel(Rel {
+            typ: RelocationType::Abs64,
+            addend: 2,
+            needed: 5,
+            offset: 5,
+        });
+
+        // v2: `foo` in text, `bar` in data
+        v2.push_string("foo");
+        v2.push_string("bar");
+        v2.push_section(SectionHeader {
+            name: NO_NAME,
+            typ: SectionType::Data,
+            flags: PermissionFlags::R | PermissionFlags::W,
+            file_size: 4,
+            offset: 0,
+            memory: 0,
+        });
+        v2.data_mut().extend_from_slice(b"data");
+        v2.push_section(SectionHeader {
+            name: NO_NAME,
+            typ: SectionType::Text,
+            flags: PermissionFlags::R | PermissionFlags::X,
+            file_size: 1,
+            offset: 4,
+            memory: 0,
+        });
+        v2.data_mut().push(0xc3);
+        v2.push_sym(Sym {
+            name: 0,
+            size: 1,
+            section: 1,
+            value: 0,
+            binding: SymbolBinding::Global,
+            kind: SymbolKind::None,
+        });
+        v2.push_sym(Sym {
+            name: 5,
+            size: 4,
+            section: 0,
+            value: 0,
+            binding: SymbolBinding::Global,
+            kind: SymbolKind::None,
+        });
+
+        let mut objs = [v1, v2];
+        let filenames = ["v1.o".to_string(), "v2.o".to_string()];
+        let symbols = resolve_symbols(&filenames, &objs).unwrap();
+        let placements = merge_sections(&mut out, &mut objs, &mut StrTabBuilder::new()).unwrap();
+        relocate(&mut out, &objs, &placements, &symbols).unwrap();
+
+        // Text is [v1 (13 bytes), padding, v2 (1 byte) at 16], data follows at 17
+        let call = i32::from_le_bytes(out.data()[1..5].try_into().unwrap());
+        assert_eq!(call, 16 - 5);
+        let ptr = u64::from_le_bytes(out.data()[5..13].try_into().unwrap());
+        assert_eq!(ptr, 17 + 2);
+    }
+
+    #[test]
+    fn test_relocate_after_bss() {
+        let mut v1 = Vsbf::empty();
+        let mut out = Vsbf::empty();
+
+        // A BSS section takes no space in the file, so it mustn't claim the
+        // relocation at the start of `.text`
+        v1.push_string("x");
+        v1.push_section(SectionHeader {
+            name: NO_NAME,
+            typ: SectionType::Bss,
+            flags: PermissionFlags::R | PermissionFlags::W,
+            file_size: 0x100,
+            offset: 0,
+            memory: 0,
+        });
+        v1.push_section(SectionHeader {
+            name: NO_NAME,
+            typ: SectionType::Text,
+            flags: PermissionFlags::R | PermissionFlags::X,
+            file_size: 8,
+            offset: 0,
+            memory: 0,
+        });
+        v1.data_mut().extend_from_slice(&[0; 8]);
+        v1.push_sym(Sym {
+            name: 0,
+            size: 8,
+            section: 1,
+            value: 0,
+            binding: SymbolBinding::Global,
+            kind: SymbolKind::None,
+        });
+        v1.push_rel(Rel {
+            typ: RelocationType::Abs64,
+            addend: 7,
+            needed: 0,
+            offset: 0,
+        });
+        assert_eq!(v1.validate(), vec![]);
+
+        let mut objs = [v1];
+        let filenames = ["v1.o".to_string()];
+        let symbols = resolve_symbols(&filenames, &objs).unwrap();
+        let placements = merge_sections(&mut out, &mut objs, &mut StrTabBuilder::new()).unwrap();
+        relocate(&mut out, &objs, &placements, &symbols).unwrap();
+
+        let ptr = u64::from_le_bytes(out.data()[0..8].try_into().unwrap());
+        assert_eq!(ptr, 7);
+    }
+
+    #[test]
+    fn test_resolve_symbols() {
+        let mut v1 = Vsbf::empty();
+        let mut v2 = Vsbf::empty();
+
+        v1.push_string("foo");
+        v1.push_string("missing");
+        v1.push_sym(Sym {
+            name: 0,
+            size: 0,
+            section: 0,
+            value: 4,
+            binding: SymbolBinding::Global,
+            kind: SymbolKind::None,
+        });
+        v1.push_rel(Rel {
+            typ: RelocationType::Pc32,
+            addend: -4,
+            needed: 5,
+            offset: 0x10,
+        });
+
+        v2.push_string("foo");
+        v2.push_sym(Sym {
+            name: 0,
+            size: 0,
+            section: 1,
+            value: 8,
+            binding: SymbolBinding::Global,
+            kind: SymbolKind::None,
+        });
+        v2.push_rel(Rel {
+            typ: RelocationType::Pc32,
+            addend: -4,
+            needed: 0,
+            offset: 0x20,
+        });
+
+        let filenames = ["v1.o".to_string(), "v2.o".to_string()];
+
+        let errors = resolve_symbols(&filenames, &[v1.clone(), v2.clone()]).unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                "duplicate symbol `foo`: defined in v1.o (section 0, offset 0x4) and in v2.o (section 1, offset 0x8)",
+                "undefined symbol `missing`, referenced by v1.o at offset 0x10",
+            ]
+        );
+
+        let symbols = resolve_symbols(&filenames[1..], &[v2]).unwrap();
+        assert_eq!(
+            symbols.globals["foo"],
+            Definition {
+                obj: 0,
+                section: 1,
+                value: 8,
+                size: 0,
+                binding: SymbolBinding::Global,
+                kind: SymbolKind::None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_resolve_symbols_binding() {
+        let sym = |section, binding| Sym {
+            name: 0,
+            size: 0,
+            section,
+            value: 0,
+            binding,
+            kind: SymbolKind::Func,
+        };
+        let rel = Rel {
+            typ: RelocationType::Pc32,
+            addend: -4,
+            needed: 0,
+            offset: 0,
+        };
+
+        // v1 has a weak `hook` and a local `helper`
+        let mut v1 = Vsbf::empty();
+        v1.push_string("hook");
+        v1.push_string("helper");
+        v1.push_sym(sym(0, SymbolBinding::Weak));
+        v1.push_sym(Sym {
+            name: 6,
+            ..sym(1, SymbolBinding::Local)
+        });
+        v1.push_rel(Rel { needed: 6, ..rel });
+
+        // v2 overrides `hook`, and has its own local `helper`
+        let mut v2 = v1.clone();
+        v2.syms_mut()[0] = sym(2, SymbolBinding::Global);
+        v2.syms_mut()[1].section = 3;
+
+        // v3 has another weak `hook`, which is ignored, and refers to `helper`
+        // without defining it
+        let mut v3 = Vsbf::empty();
+        v3.push_string("hook");
+        v3.push_string("helper");
+        v3.push_sym(sym(4, SymbolBinding::Weak));
+        v3.push_rel(rel);
+        v3.push_rel(Rel { needed: 6, ..rel });
+
+        let filenames = ["v1.o".to_string(), "v2.o".to_string(), "v3.o".to_string()];
+        let objs = [v1, v2, v3];
+
+        let errors = resolve_symbols(&filenames, &objs).unwrap_err();
+        assert_eq!(
+            errors,
+            ["undefined symbol `helper`, referenced by v3.o at offset 0x0"]
+        );
+
+        let symbols = resolve_symbols(&filenames[..2], &objs[..2]).unwrap();
+        assert_eq!(symbols.globals["hook"].section, 2);
+        assert_eq!(symbols.lookup(0, "hook").unwrap().section, 2);
+        assert_eq!(symbols.lookup(0, "helper").unwrap().section, 1);
+        assert_eq!(symbols.lookup(1, "helper").unwrap().section, 3);
+        assert!(!symbols.globals.contains_key("helper"));
+    }
+
+    #[test]
+    fn test_resolve_symbols_reserved() {
+        let sym = |name, section, value, size, binding| Sym {
+            name,
+            size,
+            section,
+            value,
+            binding,
+            kind: SymbolKind::Object,
+        };
+
+        let mut v1 = Vsbf::empty();
+        v1.push_string("opt");
+        v1.push_string("CONST");
+        v1.push_string("buf");
+        v1.push_string("small");
+        v1.push_sym(sym(0, SECTION_UNDEF, 0, 0, SymbolBinding::Weak));
+        v1.push_sym(sym(5, SECTION_ABS, 0x42, 0, SymbolBinding::Global));
+        v1.push_sym(sym(12, SECTION_COMMON, 4, 4, SymbolBinding::Global));
+        v1.push_sym(sym(17, SECTION_COMMON, 1, 1, SymbolBinding::Global));
+
+        let mut v2 = Vsbf::empty();
+        v2.push_string("buf");
+        v2.push_string("CONST");
+        v2.push_sym(sym(0, SECTION_COMMON, 8, 16, SymbolBinding::Global));
+        v2.push_sym(sym(5, SECTION_UNDEF, 0, 0, SymbolBinding::Global));
+
+        let filenames = ["v1.o".to_string(), "v2.o".to_string()];
+        let mut objs = vec![v1, v2];
+        let mut symbols = resolve_symbols(&filenames, &objs).unwrap();
+
+        let abs = |value| (SECTION_ABS, value);
+        let get = |symbols: &SymbolTable, name| {
+            let def = symbols.globals[name];
+            (def.section, def.value)
+        };
+        assert_eq!(get(&symbols, "opt"), abs(0));
+        assert_eq!(get(&symbols, "CONST"), abs(0x42));
+        assert_eq!(get(&symbols, "buf"), (SECTION_COMMON, 8));
+        assert_eq!(symbols.globals["buf"].size, 16);
+
+        // `buf` goes first as it sorts before `small`
+        allocate_commons(&mut symbols, &mut objs).unwrap();
+        assert_eq!(objs.len(), 3);
+        assert_eq!(objs[2].sections()[0].file_size, 17);
+        assert_eq!(symbols.globals["buf"].obj, 2);
+        assert_eq!(get(&symbols, "buf"), (0, 0));
+        assert_eq!(get(&symbols, "small"), (0, 16));
+
+        let placements =
+            merge_sections(&mut Vsbf::empty(), &mut objs, &mut StrTabBuilder::new()).unwrap();
+        assert_eq!(placements[2].len(), 1);
+    }
+
+    #[test]
+    fn test_merge_sections_bss() {
+        let mut v1 = Vsbf::empty();
+        let mut v2 = Vsbf::empty();
+        let mut out = Vsbf::empty();
+
+        v1.push_section(SectionHeader {
+            name: NO_NAME,
+            typ: SectionType::Bss,
+            flags: PermissionFlags::R | PermissionFlags::W,
+            file_size: 0x1000,
+            offset: 0,
+            memory: 0,
+        });
+        v1.push_section(SectionHeader {
+            name: NO_NAME,
+            typ: SectionType::Data,
+            flags: PermissionFlags::R | PermissionFlags::W,
+            file_size: 4,
+            offset: 0,
+            memory: 0,
+        });
+        v1.data_mut().extend_from_slice(b"aaaa");
+
+        v2.push_section(SectionHeader {
+            name: NO_NAME,
+            typ: SectionType::Bss,
+            flags: PermissionFlags::R | PermissionFlags::W,
+            file_size: 0x10,
+            offset: 0,
+            memory: 0,
+        });
+
+        let mut objs = [v1, v2];
+        let placements = merge_sections(&mut out, &mut objs, &mut StrTabBuilder::new()).unwrap();
+        allocate_segments(&mut out);
+
+        assert_eq!(out.data(), b"aaaa");
+        assert!(out.sections()[1].is_bss());
+        assert_eq!(out.sections()[1].offset, 4);
+        assert_eq!(out.sections()[1].file_size, 0x1010);
+        assert_eq!(placements[0][0].unwrap().offset, 0);
+        assert_eq!(placements[1][0].unwrap().offset, 0x1000);
+
+        // The BSS is right after the data, so it extends the same segment
+        assert_eq!(out.segments().len(), 1);
+        assert_eq!(out.segments()[0].file_size, 4);
+        assert_eq!(out.segments()[0].mem_size, 0x1014);
+    }
+
+    #[test]
+    fn test_layout_sections() {
+        let mut obj = Vsbf::empty();
+        let sections = [
+            (
+                SectionType::Text,
+                PermissionFlags::R | PermissionFlags::X,
+                0x1003,
+            ),
+            (
+                SectionType::Data,
+                PermissionFlags::R | PermissionFlags::W,
+                5,
+            ),
+            (SectionType::Rodata, PermissionFlags::R, 3),
+            (
+                SectionType::Bss,
+                PermissionFlags::R | PermissionFlags::W,
+                0x10,
+            ),
+        ];
+        for (typ, flags, file_size) in sections {
+            obj.push_section(SectionHeader {
+                name: NO_NAME,
+                typ,
+                flags,
+                file_size,
+                offset: 0,
+                memory: 0,
+            });
+        }
+        let mut objs = [obj];
+        objs[0].data_mut().resize(0x100b, 0);
+
+        let mut out = Vsbf::empty();
+        merge_sections(&mut out, &mut objs, &mut StrTabBuilder::new()).unwrap();
+        layout_sections(&mut out, 0x400000).unwrap();
+        allocate_segments(&mut out);
+
+        let memory: Vec<_> = out.sections().iter().map(|s| s.memory).collect();
+        assert_eq!(memory, [0x400000, 0x402000, 0x403000, 0x404000]);
+
+        let segments = out.segments();
+        assert_eq!(segments.len(), 4);
+        for (seg, sec) in segments.iter().zip(out.sections()) {
+            assert_eq!(seg.mem, sec.memory);
+            assert_eq!(seg.file, sec.offset);
+            assert_eq!(seg.flags, sec.flags);
+        }
+        assert_eq!(segments[3].file_size, 0);
+        assert_eq!(segments[3].mem_size, 0x10);
+    }
+
+    #[test]
+    fn test_layout_sections_alignment() {
+        let mut out = Vsbf::empty();
+        for typ in [SectionType::Data, SectionType::Bss] {
+            out.push_section(SectionHeader {
+                name: NO_NAME,
+                typ,
+                flags: PermissionFlags::R | PermissionFlags::W,
+                file_size: 3,
+                offset: 0,
+                memory: 0,
+            });
+        }
+
+        // Same permissions: no page break, only the section alignment
+        layout_sections(&mut out, 0x10000).unwrap();
+        assert_eq!(out.sections()[0].memory, 0x10000);
+        assert_eq!(out.sections()[1].memory, 0x10008);
+    }
+
+    #[test]
+    fn test_layout_sections_overflow() {
+        let mut out = Vsbf::empty();
+        out.push_section(SectionHeader {
+            name: NO_NAME,
+            typ: SectionType::Bss,
+            flags: PermissionFlags::R | PermissionFlags::W,
+            file_size: 0x2000,
+            offset: 0,
+            memory: 0,
+        });
+
+        assert_eq!(
+            layout_sections(&mut out, 0xffff_ffff_ffff_f000),
+            Err("sections don't fit above the image base".to_string())
+        );
+        assert!(layout_sections(&mut out, 0xffff_ffff_ffff_d000).is_ok());
+    }
+
+    #[test]
+    fn test_write_map() {
+        let mut objs = [Vsbf::empty(), Vsbf::empty()];
+        for (obj, name) in objs.iter_mut().zip(["_start", "helper"]) {
+            obj.push_string(name);
+            obj.push_section(SectionHeader {
+                name: NO_NAME,
+                typ: SectionType::Text,
+                flags: PermissionFlags::R | PermissionFlags::X,
+                file_size: 0x10,
+                offset: 0,
+                memory: 0,
+            });
+            obj.data_mut().resize(0x10, 0x90);
+            obj.push_sym(Sym {
+                name: 0,
+                size: 4,
+                section: 0,
+                value: 2,
+                binding: SymbolBinding::Global,
+                kind: SymbolKind::Func,
+            });
+        }
+        let filenames = ["a.o".to_string(), "b.o".to_string()];
+
+        let symbols = resolve_symbols(&filenames, &objs).unwrap();
+        let mut out = Vsbf::empty();
+        let mut strtab = StrTabBuilder::new();
+        let placements = merge_sections(&mut out, &mut objs, &mut strtab).unwrap();
+        layout_sections(&mut out, 0x400000).unwrap();
+        emit_symbols(&mut out, &symbols, &placements, &mut strtab);
+        out.set_strtab(strtab.finish());
+        allocate_segments(&mut out);
+
+        let mut map = vec![];
+        write_map(&mut map, &out, &filenames, &objs, &placements, &symbols).unwrap();
+        let map = String::from_utf8(map).unwrap();
+        let lines: Vec<_> = map.lines().map(str::trim).collect();
+
+        assert!(lines.contains(&"0x0000000000400000 0x00000020 0x00000020 R X"));
+        assert!(lines.contains(&".text        0x0000000000400000 0x00000020 R X"));
+        assert!(lines.contains(&"0x0000000000400010 0x00000010 b.o (section 0, offset 0x10)"));
+
+        let start = lines.iter().position(|l| l.ends_with("_start (a.o)"));
+        let helper = lines.iter().position(|l| l.ends_with("helper (b.o)"));
+        assert!(start.unwrap() < helper.unwrap());
+        assert!(lines.contains(&"0x0000000000400012 0x0004 global helper (b.o)"));
+
+        // The output's symbols have their final address
+        assert_eq!(out.syms().len(), 2);
+
+        let helper = out.find_symbol("helper").unwrap();
+        assert_eq!((helper.section, helper.value), (0, 0x12));
+        assert_eq!(out.symbol_at_address(0x400013), Some((helper, 1)));
+    }
+
+    #[test]
+    fn test_gc_sections() {
+        let section = |typ, offset| SectionHeader {
+            name: NO_NAME,
+            typ,
+            flags: PermissionFlags::R,
+            file_size: 8,
+            offset,
+            memory: 0,
+        };
+        let sym = |name, section| Sym {
+            name,
+            size: 8,
+            section,
+            value: 0,
+            binding: SymbolBinding::Global,
+            kind: SymbolKind::None,
+        };
+
+        // _start uses `used`, nothing uses `unused`, `kept` is kept explicitly
+        let mut v1 = Vsbf::empty();
+        v1.push_string("_start");
+        v1.push_string("unused");
+        v1.push_string("used");
+        v1.push_section(section(SectionType::Text, 0));
+        v1.push_section(section(SectionType::Text, 8));
+        v1.data_mut().resize(16, 0);
+        v1.push_sym(sym(0, 0));
+        v1.push_sym(sym(8, 1));
+        v1.push_rel(Rel {
+            typ: RelocationType::Abs64,
+            addend: 0,
+            needed: 16,
+            offset: 0,
+        });
+        // Relocations in dead sections don't make anything live
+        v1.push_rel(Rel {
+            typ: RelocationType::Abs64,
+            addend: 0,
+            needed: 16,
+            offset: 8,
+        });
+
+        let mut v2 = Vsbf::empty();
+        v2.push_string("used");
+        v2.push_string("kept");
+        v2.push_string("dead");
+        v2.push_section(section(SectionType::Data, 0));
+        v2.push_section(section(SectionType::Rodata, 8));
+        v2.push_section(section(SectionType::Rodata, 16));
+        v2.data_mut().resize(24, 0);
+        v2.push_sym(sym(0, 0));
+        v2.push_sym(sym(6, 1));
+        v2.push_sym(sym(12, 2));
+
+        let filenames = ["v1.o".to_string(), "v2.o".to_string()];
+        let mut objs = [v1, v2];
+        let symbols = resolve_symbols(&filenames, &objs).unwrap();
+
+        let live = live_sections(&objs, &symbols, &["_start", "kept"]).unwrap();
+        assert_eq!(live, [vec![true, false], vec![true, true, false]]);
+
+        let mut out = Vsbf::empty();
+        let mut strtab = StrTabBuilder::new();
+        let placements = merge_live_sections(&mut out, &mut objs, &live, &mut strtab).unwrap();
+        assert_eq!(placements[0][1], None);
+        assert_eq!(placements[1][2], None);
+        assert!(is_discarded(&placements, &symbols.globals["unused"]));
+        assert!(!is_discarded(&placements, &symbols.globals["used"]));
+        assert_eq!(out.data().len(), 24);
+        relocate(&mut out, &objs, &placements, &symbols).unwrap();
+
+        // Names of removed symbols don't make it to the output's string table
+        emit_symbols(&mut out, &symbols, &placements, &mut strtab);
+        out.set_strtab(strtab.finish());
+        let strings: Vec<_> = out.strings().map(|s| s.unwrap().1).collect();
+        assert_eq!(
+            strings,
+            [".text", ".data", ".rodata", "_start", "kept", "used"]
+        );
+
+        assert!(live_sections(&objs, &symbols, &[]).is_err());
+        assert_eq!(
+            live_sections(&objs, &symbols, &["nope"]),
+            Err("cannot keep undefined symbol `nope`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_gc_keeps_init_array() {
+        let mut obj = Vsbf::empty();
+        let sections = [
+            (".text", SectionType::Text),
+            (".text.ctor", SectionType::Text),
+            (".text.unused", SectionType::Text),
+            (".init_array", SectionType::Data),
+        ];
+        for (i, (name, typ)) in sections.into_iter().enumerate() {
+            let name = obj.push_string(name);
+            obj.push_section(SectionHeader {
+                name,
+                typ,
+                flags: PermissionFlags::R,
+                file_size: 8,
+                offset: i as u32 * 8,
+                memory: 0,
+            });
+        }
+        obj.data_mut().resize(32, 0);
+        for (name, section) in [("_start", 0), ("ctor", 1), ("unused", 2)] {
+            let name = obj.push_string(name);
+            obj.push_sym(Sym {
+                name,
+                size: 8,
+                section,
+                value: 0,
+                binding: SymbolBinding::Global,
+                kind: SymbolKind::None,
+            });
+        }
+        // The constructor table points to `ctor`, which nothing else uses
+        let ctor = obj.find_symbol("ctor").unwrap().name;
+        obj.push_rel(Rel {
+            typ: RelocationType::Abs64,
+            addend: 0,
+            needed: ctor,
+            offset: 24,
+        });
+
+        let objs = [obj];
+        let symbols = resolve_symbols(&["a.o".to_string()], &objs).unwrap();
+        let live = live_sections(&objs, &symbols, &["_start"]).unwrap();
+        assert_eq!(live, [vec![true, true, false, true]]);
+    }
+
+    #[test]
+    fn test_pull_members() {
+        // Each object defines `names[0]` and refers to the others
+        let object = |names: &[&str]| {
+            let mut obj = Vsbf::empty();
+            let mut offset = 0;
+            for (i, name) in names.iter().enumerate() {
+                obj.push_string(name);
+                obj.push_sym(Sym {
+                    name: offset,
+                    size: 0,
+                    section: if i == 0 { SECTION_ABS } else { SECTION_UNDEF },
+                    value: 0,
+                    binding: SymbolBinding::Global,
+                    kind: SymbolKind::None,
+                });
+                offset += 2 + name.len() as u32;
+            }
+            let mut data = vec![];
+            obj.write(&mut data).unwrap();
+            data
+        };
+
+        let mut ar = Archive::empty();
+        ar.push_member("b.o", object(&["b"])).unwrap();
+        ar.push_member("a.o", object(&["a", "b"])).unwrap();
+        ar.push_member("unused.o", object(&["unused", "missing"]))
+            .unwrap();
+        let archives = [("lib.a".to_string(), ar)];
+
+        let mut filenames = vec!["main.o".to_string()];
+        let mut objs = vec![Vsbf::parse(&object(&["main", "a"])).unwrap().1];
+        pull_members(&archives, &mut filenames, &mut objs, false).unwrap();
+
+        assert_eq!(filenames, ["main.o", "lib.a(a.o)", "lib.a(b.o)"]);
+        assert_eq!(objs.len(), 3);
+        assert_eq!(undefined_names(&objs[0]).unwrap(), ["a"]);
+    }
+
+    #[test]
+    fn test_merge_named_sections() {
+        let mut obj = Vsbf::empty();
+        let sections = [
+            (".text.b", SectionType::Text),
+            (".init_array", SectionType::Data),
+            (".text.a", SectionType::Text),
+            (".mine", SectionType::Data),
+            (".data.x", SectionType::Data),
+        ];
+        for (i, (name, typ)) in sections.into_iter().enumerate() {
+            let name = obj.push_string(name);
+            obj.push_section(SectionHeader {
+                name,
+                typ,
+                flags: PermissionFlags::R,
+                file_size: 1,
+                offset: i as u32,
+                memory: 0,
+            });
+        }
+        obj.data_mut().extend_from_slice(b"bIaMx");
+
+        let mut out = Vsbf::empty();
+        let mut strtab = StrTabBuilder::new();
+        let placements = merge_sections(&mut out, &mut [obj], &mut strtab).unwrap();
+        out.set_strtab(strtab.finish());
+
+        let names: Vec<_> = out
+            .sections()
+            .iter()
+            .map(|sec| out.section_name(sec).unwrap().to_string())
+            .collect();
+        assert_eq!(names, [".text", ".data", ".init_array", ".mine"]);
+        assert_eq!(out.data(), b"b\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0axIM");
+
+        let sections: Vec<_> = placements[0].iter().map(|p| p.unwrap().section).collect();
+        assert_eq!(sections, [0, 2, 0, 3, 1]);
+        assert_eq!(placements[0][2].unwrap().offset, 16);
+    }
+
+    #[test]
+    fn test_merge_non_ascii_section_name() {
+        let mut obj = Vsbf::empty();
+        let mut names = StrTabBuilder::new();
+        let name = names.add(".café");
+        obj.set_strtab(names.finish());
+        obj.push_section(SectionHeader {
+            name,
+            typ: SectionType::Data,
+            flags: PermissionFlags::R,
+            file_size: 1,
+            offset: 0,
+            memory: 0,
+        });
+        obj.data_mut().push(0);
+        assert_eq!(obj.validate(), vec![]);
+
+        let mut out = Vsbf::empty();
+        let mut strtab = StrTabBuilder::new();
+        merge_sections(&mut out, &mut [obj], &mut strtab).unwrap();
+        out.set_strtab(strtab.finish());
+        assert_eq!(out.section_name(&out.sections()[0]), Ok(".café"));
+    }
+
+    #[test]
+    fn test_merge_sections_alignment() {
+        let mut v1 = Vsbf::empty();
+        let mut v2 = Vsbf::empty();
+        let mut out = Vsbf::empty();
+
+        for (obj, data) in [(&mut v1, &b"abc"[..]), (&mut v2, &b"01234567"[..])] {
+            obj.push_section(SectionHeader {
+                name: NO_NAME,
+                typ: SectionType::Data,
+                flags: PermissionFlags::R | PermissionFlags::W,
+                file_size: data.len() as u32,
+                offset: 0,
+                memory: 0,
+            });
+            obj.data_mut().extend_from_slice(data);
+        }
+
+        let placements =
+            merge_sections(&mut out, &mut [v1, v2], &mut StrTabBuilder::new()).unwrap();
+
+        // The second `.data` is 8-byte aligned, after padding the first one
+        assert_eq!(placements[1][0].unwrap().offset, 8);
+        assert_eq!(out.sections()[0].file_size, 16);
+        assert_eq!(&out.data()[..8], b"abc\0\0\0\0\0");
+        assert_eq!(&out.data()[8..], b"01234567");
+    }
+
+    #[test]
+    fn test_merge_sections_overflow() {
+        let bss = |file_size| {
+            let mut obj = Vsbf::empty();
+            obj.push_section(SectionHeader {
+                name: NO_NAME,
+                typ: SectionType::Bss,
+                flags: PermissionFlags::R | PermissionFlags::W,
+                file_size,
+                offset: 0,
+                memory: 0,
+            });
+            obj
+        };
+
+        // Sections larger than 64 KiB are fine...
+        let mut objs = [bss(0x10000), bss(0x7fff_0000)];
+        let placements =
+            merge_sections(&mut Vsbf::empty(), &mut objs, &mut StrTabBuilder::new()).unwrap();
+        assert_eq!(placements[1][0].unwrap().offset, 0x10000);
+
+        // ...but not ones that overflow
+        let mut objs = [bss(0x8000_0000), bss(0x8000_0000)];
+        assert_eq!(
+            merge_sections(&mut Vsbf::empty(), &mut objs, &mut StrTabBuilder::new()),
+            Err("output section `.bss` is larger than 4 GiB".to_string())
+        );
+    }
+
+    /// Small deterministic PRNG (xorshift64*), for randomized tests
+    struct Rng(u64);
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        /// Random number in `0..n`
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    /// An object with randomly named sections, and symbols defined in them.
+    /// Names come from small pools, so that objects share some, and some
+    /// names are stored several times in the same string table. Global
+    /// symbols use names not in `globals` yet, so that the objects link.
+    fn random_object(rng: &mut Rng, globals: &mut HashSet<&'static str>) -> Vsbf {
+        const SECTIONS: [(&str, SectionType); 7] = [
+            (".text", SectionType::Text),
+            (".text.a", SectionType::Text),
+            (".data", SectionType::Data),
+            (".data.x", SectionType::Data),
+            (".init_array", SectionType::Data),
+            (".mine", SectionType::Rodata),
+            (".rodata.b", SectionType::Rodata),
+        ];
+        const SYMBOLS: [&str; 8] = ["", "a", "b", "main", "_start", "printf", "buf", "a.b"];
+
+        let mut obj = Vsbf::empty();
+        let num_sections = 1 + rng.below(4);
+        for _ in 0..num_sections {
+            let (name, typ) = SECTIONS[rng.below(SECTIONS.len())];
+            let name = obj.push_string(name);
+            let offset = obj.data().len() as u32;
+            let size = 1 + rng.below(16);
+            obj.data_mut().resize(offset as usize + size, 0);
+            obj.push_section(SectionHeader {
+                name,
+                typ,
+                flags: PermissionFlags::R,
+                file_size: size as u32,
+                offset,
+                memory: 0,
+            });
+        }
+
+        for _ in 0..rng.below(12) {
+            let name = SYMBOLS[rng.below(SYMBOLS.len())];
+            let binding = match rng.below(3) {
+                0 if globals.insert(name) => SymbolBinding::Global,
+                1 => SymbolBinding::Weak,
+                _ => SymbolBinding::Local,
+            };
+            let section = rng.below(num_sections);
+            let value = rng.below(obj.sections()[section].file_size as usize);
+            let name = obj.push_string(name);
+            obj.push_sym(Sym {
+                name,
+                size: 0,
+                section: section as u16,
+                value: value as u64,
+                binding,
+                kind: SymbolKind::None,
+            });
+        }
+
+        obj
+    }
+
+    #[test]
+    fn test_output_strtab_random() {
+        let mut rng = Rng(0x5eed_1234_abcd_0001);
+
+        for _ in 0..200 {
+            let mut globals = HashSet::new();
+            let mut objs: Vec<_> = (0..1 + rng.below(5))
+                .map(|_| random_object(&mut rng, &mut globals))
+                .collect();
+            let filenames: Vec<_> = (0..objs.len()).map(|i| format!("{i}.o")).collect();
+
+            // The names the output should have, each with its section type
+            let mut sections: Vec<_> = objs
+                .iter()
+                .flat_map(|obj| {
+                    let sections = obj.sections();
+                    sections
+                        .into_iter()
+                        .map(|sec| {
+                            (
+                                output_section(section_name(obj, &sec)).1.to_string(),
+                                sec.typ,
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect();
+            sections.sort_by_key(|(name, typ)| (name.clone(), *typ as u8));
+
+            let symbols = resolve_symbols(&filenames, &objs).unwrap();
+            let mut names: Vec<_> = symbols.globals.keys().cloned().collect();
+            for locals in &symbols.locals {
+                names.extend(locals.keys().cloned());
+            }
+            names.sort();
+
+            let mut out = Vsbf::empty();
+            let mut strtab = StrTabBuilder::new();
+            let placements = merge_sections(&mut out, &mut objs, &mut strtab).unwrap();
+            emit_symbols(&mut out, &symbols, &placements, &mut strtab);
+            out.set_strtab(strtab.finish());
+
+            // Every emitted name resolves to its original string...
+            let mut out_sections: Vec<_> = out
+                .sections()
+                .iter()
+                .map(|sec| (out.section_name(sec).unwrap().to_string(), sec.typ))
+                .collect();
+            out_sections.sort_by_key(|(name, typ)| (name.clone(), *typ as u8));
+            assert_eq!(out_sections, sections);
+
+            let mut out_names: Vec<_> = out
+                .syms()
+                .iter()
+                .map(|sym| out.string_at(sym.name).unwrap().to_string())
+                .collect();
+            out_names.sort();
+            assert_eq!(out_names, names);
+
+            // ...which is stored only once
+            let strings: Vec<_> = out.strings().map(|s| s.unwrap().1).collect();
+            let unique: HashSet<_> = strings.iter().collect();
+            assert_eq!(strings.len(), unique.len());
+        }
     }
 }
@@ -1,4 +1,13 @@
-use std::{env::args, ffi::OsStr, os::unix::ffi::OsStrExt, u64};
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    env::{args, vars_os},
+    ffi::CString,
+    io::{self, Read},
+    os::unix::ffi::OsStrExt,
+    process::exit,
+    rc::Rc,
+};
 
 use capstone::{
     arch::{
@@ -10,16 +19,238 @@ use capstone::{
 use unicorn_engine::{
     Arch,
     InsnSysX86::SYSCALL,
-    Mode, Permission,
-    RegisterX86::{RAX, RDI, RDX, RSI, RSP},
+    Mode, Permission, Query,
+    RegisterX86::{R10, R8, R9, RAX, RDI, RDX, RSI, RSP},
     Unicorn, SECOND_SCALE,
 };
-use vsbf::Vsbf;
+use vsbf::{PermissionFlags, SegmentHeader, Vsbf};
+
+const USAGE: &str = "\
+usage: loader [options] <file> [-- <args>...]
+
+Options:
+  --native            Run the program on the host CPU instead of emulating it
+  --stack-size <size> Size of the program's stack (default 0x10000)
+  --insn-limit <n>    Stop emulation after <n> instructions (default 0, no limit)
+  --env <key>=<value> Set an environment variable for the program
+  --clear-env         Don't pass the loader's environment to the program";
+
+/// Default size of the stack given to the loaded program
+const DEFAULT_STACK_SIZE: u64 = 0x10000;
+
+/// How long the emulated program may run
+const EMU_TIMEOUT: u64 = 10 * SECOND_SCALE;
+/// Address right above the emulated program's stack
+const EMU_STACK_TOP: u64 = 0x8000000;
+
+/// Exit status when emulation reaches [`EMU_TIMEOUT`], as with timeout(1)
+const EXIT_TIMEOUT: i32 = 124;
+/// Exit status when emulation reaches the `--insn-limit`
+const EXIT_INSN_LIMIT: i32 = 125;
+/// Exit status when the emulated CPU faults, as if killed by SIGSEGV
+const EXIT_FAULT: i32 = 139;
+
+// Auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+struct Options {
+    native: bool,
+    stack_size: u64,
+    /// Maximum number of instructions to emulate, or 0 for no limit
+    insn_limit: usize,
+    path: String,
+    /// Arguments for the program, including its own name
+    argv: Vec<CString>,
+    /// Environment for the program, as `key=value` strings
+    envp: Vec<CString>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut args = args.into_iter();
+    let mut native = false;
+    let mut stack_size = DEFAULT_STACK_SIZE;
+    let mut insn_limit = 0;
+    let mut path = None;
+    let mut env = Vec::new();
+    let mut clear_env = false;
+    let mut program_args = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--native" => native = true,
+            "--stack-size" => {
+                let size = args.next().ok_or("missing argument for `--stack-size`")?;
+                stack_size =
+                    parse_size(&size).ok_or_else(|| format!("invalid stack size `{size}`"))?;
+                // The emulated stack ends at `EMU_STACK_TOP`, and can't wrap around
+                if !(1..=EMU_STACK_TOP).contains(&stack_size) {
+                    return Err(format!(
+                        "stack size must be between 1 and 0x{EMU_STACK_TOP:x} bytes"
+                    ));
+                }
+            }
+            "--insn-limit" => {
+                let limit = args.next().ok_or("missing argument for `--insn-limit`")?;
+                insn_limit = parse_size(&limit)
+                    .and_then(|limit| usize::try_from(limit).ok())
+                    .ok_or_else(|| format!("invalid instruction limit `{limit}`"))?;
+            }
+            "--env" => {
+                let var = args.next().ok_or("missing argument for `--env`")?;
+                if !var.contains('=') {
+                    return Err(format!("expected `key=value` for `--env`, found `{var}`"));
+                }
+                env.push(var);
+            }
+            "--clear-env" => clear_env = true,
+            "--" => {
+                program_args.extend(args.by_ref());
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{arg}`")),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("unexpected argument `{arg}`")),
+        }
+    }
+    let path = path.ok_or("missing input file")?;
+
+    let mut envp: Vec<Vec<u8>> = Vec::new();
+    if !clear_env {
+        for (key, value) in vars_os() {
+            envp.push([key.as_bytes(), b"=", value.as_bytes()].concat());
+        }
+    }
+    // Variables given on the command line replace inherited ones
+    for var in env {
+        let key = &var.as_bytes()[..=var.find('=').unwrap()];
+        envp.retain(|existing| !existing.starts_with(key));
+        envp.push(var.into_bytes());
+    }
+
+    let argv = std::iter::once(path.clone()).chain(program_args);
+    let to_c_string = |s: Vec<u8>| CString::new(s).map_err(|_| "argument contains a NUL byte");
+    Ok(Options {
+        native,
+        stack_size,
+        insn_limit,
+        argv: argv
+            .map(|arg| to_c_string(arg.into_bytes()))
+            .collect::<Result<_, _>>()?,
+        envp: envp
+            .into_iter()
+            .map(to_c_string)
+            .collect::<Result<_, _>>()?,
+        path,
+    })
+}
+
+fn parse_size(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
 
 fn main() {
-    let buf = std::fs::read(args().nth(1).unwrap()).unwrap();
-    let (data, file) = Vsbf::parse(&buf).unwrap();
+    let options = match parse_args(args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {e}\n{USAGE}");
+            exit(1);
+        }
+    };
+    let path = &options.path;
 
+    let buf = match std::fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) => {
+            eprintln!("error: cannot read `{path}`: {e}");
+            exit(1);
+        }
+    };
+    let (data, file) = match Vsbf::parse(&buf) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("error: {e}");
+            exit(1);
+        }
+    };
+
+    if options.native {
+        let Err(e) = run_native(&file, data, &options);
+        eprintln!("error: {e}");
+        exit(1);
+    } else {
+        exit(run_emulated(&file, data, &options));
+    }
+}
+
+/// Builds the initial process stack described by the System V x86-64 ABI, which
+/// has to end right below `top`.
+///
+/// From the returned stack pointer upwards, it contains argc, the NULL-terminated
+/// argv and envp arrays, the auxiliary vector, and then the strings they point to.
+/// Returns the stack pointer and the contents of the stack from there to `top`.
+fn initial_stack(
+    top: u64,
+    options: &Options,
+    entry: u64,
+    page_size: u64,
+) -> io::Result<(u64, Vec<u8>)> {
+    let mut random = [0; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+
+    let strings = options.argv.iter().chain(&options.envp);
+    let strings_len: usize = strings.map(|s| s.as_bytes_with_nul().len()).sum();
+    let info_start = (top - (strings_len + random.len()) as u64) & !0xf;
+
+    // Strings and random bytes, starting at `info_start`
+    let mut info = random.to_vec();
+    let mut place = |s: &CString| {
+        let addr = info_start + info.len() as u64;
+        info.extend_from_slice(s.as_bytes_with_nul());
+        addr
+    };
+    let argv: Vec<_> = options.argv.iter().map(&mut place).collect();
+    let envp: Vec<_> = options.envp.iter().map(&mut place).collect();
+
+    let mut words = vec![argv.len() as u64];
+    words.extend(argv);
+    words.push(0);
+    words.extend(envp);
+    words.push(0);
+    for (key, value) in [
+        (AT_PAGESZ, page_size),
+        (AT_ENTRY, entry),
+        (AT_RANDOM, info_start),
+        (AT_NULL, 0),
+    ] {
+        words.extend([key, value]);
+    }
+
+    // The stack pointer must be 16-byte aligned at the process entry point
+    let sp = (info_start - words.len() as u64 * 8) & !0xf;
+    if top - sp > options.stack_size {
+        return Err(io::Error::other(
+            "arguments and environment don't fit in the stack",
+        ));
+    }
+
+    let mut stack: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    stack.resize((info_start - sp) as usize, 0);
+    stack.extend(info);
+    stack.resize((top - sp) as usize, 0);
+    Ok((sp, stack))
+}
+
+/// Runs the program inside unicorn, tracing every instruction and syscall to
+/// stderr, so that the program's own output can be told apart.
+///
+/// Returns the status the program exited with, or one of the `EXIT_*` codes if it
+/// was stopped before exiting.
+fn run_emulated(file: &Vsbf, data: &[u8], options: &Options) -> i32 {
     let cs = Capstone::new()
         .x86()
         .mode(ArchMode::Mode64)
@@ -27,70 +258,912 @@ fn main() {
         .detail(true)
         .build()
         .expect("Failed to initialize Capstone");
-    let mut emu = Unicorn::new(Arch::X86, Mode::MODE_64).expect("Failed to init Unicorn");
+    let mut emu = Unicorn::new_with_data(Arch::X86, Mode::MODE_64, Guest::default())
+        .expect("Failed to init Unicorn");
 
     // Load file
-    for segment in file.segments() {
-        emu.mem_map(
-            segment.mem + 0x1000,
-            (segment.mem_size as usize & 0x1000) + 0x1000,
-            Permission::from_bits(segment.flags.bits() as _).unwrap(),
-        )
-        .unwrap();
-
-        let start = segment.file as usize;
-        let end = start + segment.file_size as usize;
-        emu.mem_write(segment.mem + 0x1000, &data[start..end])
-            .unwrap();
+    let segments = file.segments();
+    let regions = match plan_regions(&segments, EMU_PAGE_SIZE) {
+        Ok(regions) => regions,
+        Err(e) => {
+            eprintln!("error: {e}");
+            exit(1);
+        }
+    };
+    for region in &regions {
+        let perms = Permission::from_bits_truncate(region.flags.bits() as _);
+        if let Err(e) = emu.mem_map(region.start, (region.end - region.start) as usize, perms) {
+            eprintln!("error: cannot map segment {}: {e:?}", region.segment);
+            exit(1);
+        }
+    }
+    for (i, segment) in segments.iter().enumerate() {
+        if let Err(e) = load_segment(&mut emu, segment, data) {
+            eprintln!("error: cannot load segment {i}: {e}");
+            exit(1);
+        }
     }
+    // The heap starts right after the highest segment
+    let end = regions.iter().map(|region| region.end).max().unwrap_or(0);
+    emu.get_data_mut().brk_start = end;
+    emu.get_data_mut().brk = end;
 
     // Setup stack
-    emu.mem_map(0x7fff000, 0x1000, Permission::READ | Permission::WRITE)
-        .unwrap();
-    emu.reg_write(RSP, 0x8000000).unwrap();
+    let stack_size = page_align(options.stack_size);
+    let stack_bottom = match emu_stack_bottom(&regions, stack_size) {
+        Ok(bottom) => bottom,
+        Err(e) => {
+            eprintln!("error: {e}");
+            exit(1);
+        }
+    };
+    let perms = Permission::READ | Permission::WRITE;
+    if let Err(e) = emu.mem_map(stack_bottom, stack_size as usize, perms) {
+        eprintln!("error: cannot map stack: {e:?}");
+        exit(1);
+    }
+    let (sp, stack) = match initial_stack(EMU_STACK_TOP, options, file.entry(), EMU_PAGE_SIZE) {
+        Ok(stack) => stack,
+        Err(e) => {
+            eprintln!("error: cannot set up stack: {e}");
+            exit(1);
+        }
+    };
+    emu.mem_write(sp, &stack).unwrap();
+    emu.reg_write(RSP, sp).unwrap();
 
-    // Disassemble every instruction
-    let code = emu.mem_read_as_vec(0x1000, 0x1000).unwrap();
-    emu.add_code_hook(0, u64::MAX, move |_, addr, _| {
-        let disasm = cs
-            .disasm_count(&code[addr as usize - 0x1000..], addr, 1)
-            .unwrap();
+    // Disassemble every instruction, with a label at the start of each symbol
+    let syms = file.clone();
+    let executed = Rc::new(Cell::new(0));
+    let counter = executed.clone();
+    emu.add_code_hook(0, u64::MAX, move |emu, addr, size| {
+        counter.set(counter.get() + 1);
+        if let Some((sym, 0)) = syms.symbol_at_address(addr) {
+            eprintln!("<{}>:", syms.string_at(sym.name).unwrap_or("<invalid>"));
+        }
+
+        let code = emu.mem_read_as_vec(addr, size as usize).unwrap();
+        let disasm = cs.disasm_count(&code, addr, 1).unwrap();
 
-        print!("{}", disasm);
+        eprint!("{}", disasm);
     })
     .unwrap();
 
-    emu.add_insn_sys_hook(SYSCALL, 0x1000, u64::MAX, syscall)
+    let syscalls = SyscallTable::linux();
+    emu.add_insn_sys_hook(SYSCALL, 0, u64::MAX, move |emu| syscalls.dispatch(emu))
         .unwrap();
 
-    emu.emu_start(
-        0x1000,
-        file.segments()[0].file_size as u64 + 0x1000,
-        10 * SECOND_SCALE,
-        1000,
-    )
-    .unwrap();
+    let result = emu.emu_start(file.entry(), 0, EMU_TIMEOUT, options.insn_limit);
+    if let Some(status) = emu.get_data().exit_code {
+        return status;
+    }
+
+    let pc = emu.pc_read().unwrap_or(0);
+    let location = match file.symbol_at_address(pc) {
+        Some((sym, offset)) => {
+            let name = file.string_at(sym.name).unwrap_or("<invalid>");
+            format!("0x{pc:x} <{name}+0x{offset:x}>")
+        }
+        None => format!("0x{pc:x}"),
+    };
+    match result {
+        Err(e) => {
+            eprintln!("fault: {e:?} at {location}");
+            EXIT_FAULT
+        }
+        Ok(())
+            if emu
+                .query(Query::TIMEOUT)
+                .is_ok_and(|timed_out| timed_out != 0) =>
+        {
+            eprintln!("timeout: program still running at {location}");
+            EXIT_TIMEOUT
+        }
+        Ok(()) if options.insn_limit != 0 && executed.get() >= options.insn_limit => {
+            eprintln!("instruction limit: program still running at {location}");
+            EXIT_INSN_LIMIT
+        }
+        // Emulation also stops cleanly when the program jumps to address 0
+        Ok(()) => {
+            eprintln!("fault: jump to {location}");
+            EXIT_FAULT
+        }
+    }
 }
 
-fn syscall(emu: &mut Unicorn<'_, ()>) {
-    let rax = emu.reg_read(RAX).unwrap();
-    let rdi = emu.reg_read(RDI).unwrap();
-    let rsi = emu.reg_read(RSI).unwrap();
-    let rdx = emu.reg_read(RDX).unwrap();
+/// A range of memory mapped with the same permissions, covering one or more segments
+struct Region {
+    start: u64,
+    end: u64,
+    flags: PermissionFlags,
+    /// The first segment in this region, for error messages
+    segment: usize,
+}
+
+/// Granularity of unicorn's memory mappings
+const EMU_PAGE_SIZE: u64 = 0x1000;
 
-    match rax {
-        1 => {
-            let buf = emu.mem_read_as_vec(rsi, rdx as _).unwrap();
-            let buf = OsStr::from_bytes(&buf);
-            println!("SYSCALL: write({rdi}, {buf:?}, {rdx})")
+/// Computes the memory to map for the given segments.
+///
+/// Every segment is extended to its alignment (and at least to a page). Segments
+/// whose extended ranges overlap are merged if they have the same permissions, and
+/// rejected otherwise, since a page can only have one set of permissions.
+fn plan_regions(segments: &[SegmentHeader], page_size: u64) -> Result<Vec<Region>, String> {
+    let mut ranges = Vec::with_capacity(segments.len());
+    for (i, segment) in segments.iter().enumerate() {
+        let align = u64::from(segment.align).max(page_size);
+        if !align.is_power_of_two() {
+            return Err(format!(
+                "segment {i} has invalid alignment {:#x}",
+                segment.align
+            ));
         }
-        0x3c => {
-            println!("SYSCALL: exit({rdi})");
-            emu.emu_stop().unwrap();
+        if segment.mem_size == 0 {
+            continue;
         }
-        _ => {
-            println!("SYSCALL {rax:x} {rdi:x} {rsi:x} {rdx:x}");
-            todo!()
+
+        let start = segment.mem & !(align - 1);
+        let end = segment
+            .mem
+            .checked_add(u64::from(segment.mem_size))
+            .and_then(|end| end.checked_next_multiple_of(align))
+            .ok_or_else(|| format!("segment {i} extends past the end of the address space"))?;
+        ranges.push((start, end, segment.flags, i));
+    }
+    ranges.sort_by_key(|&(start, ..)| start);
+
+    let mut regions: Vec<Region> = Vec::new();
+    let mut last_segment = 0;
+    for (start, end, flags, i) in ranges {
+        if let Some(last) = regions.last_mut().filter(|last| start < last.end) {
+            if last.flags != flags {
+                return Err(format!(
+                    "segment {i} ({flags}) shares pages with segment {last_segment} ({})",
+                    last.flags
+                ));
+            }
+            last.end = last.end.max(end);
+        } else {
+            regions.push(Region {
+                start,
+                end,
+                flags,
+                segment: i,
+            });
         }
+        last_segment = i;
+    }
+
+    Ok(regions)
+}
+
+/// Lowest address of an emulated stack of `stack_size` bytes, checking that it
+/// doesn't overlap any of the program's `regions`
+fn emu_stack_bottom(regions: &[Region], stack_size: u64) -> Result<u64, String> {
+    let bottom = EMU_STACK_TOP - stack_size;
+    let overlap = regions
+        .iter()
+        .find(|region| region.start < EMU_STACK_TOP && region.end > bottom);
+    match overlap {
+        Some(region) => Err(format!(
+            "a stack of 0x{stack_size:x} bytes would overlap segment {}",
+            region.segment
+        )),
+        None => Ok(bottom),
+    }
+}
+
+/// Copies a segment's contents into (already mapped) emulator memory
+fn load_segment(
+    emu: &mut Unicorn<'_, Guest>,
+    segment: &SegmentHeader,
+    data: &[u8],
+) -> Result<(), String> {
+    if segment.file_size > segment.mem_size {
+        return Err("file size is bigger than memory size".to_string());
+    }
+    let start = segment.file as usize;
+    let contents = start
+        .checked_add(segment.file_size as usize)
+        .and_then(|end| data.get(start..end))
+        .ok_or("contents out of file bounds")?;
+    emu.mem_write(segment.mem, contents)
+        .map_err(|e| format!("{e:?}"))?;
+
+    // Zero-fill the part of the segment that isn't stored in the file (e.g. BSS)
+    let zeroes = (segment.mem_size - segment.file_size) as usize;
+    emu.mem_write(segment.mem + u64::from(segment.file_size), &vec![0; zeroes])
+        .map_err(|e| format!("{e:?}"))
+}
+
+/// Where anonymous `mmap`s without an address hint are placed in the guest
+const MMAP_BASE: u64 = 0x7f00_0000_0000;
+/// End of the guest's address space, as for user space on x86-64 Linux
+const ADDRESS_SPACE_END: u64 = 1 << 47;
+
+/// Emulated kernel state of the guest process
+#[derive(Default)]
+struct Guest {
+    /// Initial program break: the heap can't shrink below this
+    brk_start: u64,
+    /// Current program break
+    brk: u64,
+    /// Address of the next `mmap` without a fixed address
+    mmap_next: u64,
+    /// Host file descriptors opened by the guest (stdio is always available)
+    fds: HashSet<i32>,
+    /// Status passed to `exit` or `exit_group`, once the guest has exited
+    exit_code: Option<i32>,
+}
+
+/// Implementation of a syscall: takes the six argument registers, and returns either
+/// the result or an errno value
+type SyscallFn = fn(&mut Unicorn<'_, Guest>, [u64; 6]) -> Result<u64, i32>;
+
+/// Maps syscall numbers to their implementation
+struct SyscallTable {
+    /// Name, number of arguments (for tracing) and implementation of each syscall
+    entries: HashMap<u64, (&'static str, usize, SyscallFn)>,
+}
+impl SyscallTable {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// The subset of Linux x86-64 syscalls supported by the loader
+    fn linux() -> Self {
+        let mut table = Self::new();
+        table.register(0, "read", 3, sys_read);
+        table.register(1, "write", 3, sys_write);
+        table.register(3, "close", 1, sys_close);
+        table.register(9, "mmap", 6, sys_mmap);
+        table.register(11, "munmap", 2, sys_munmap);
+        table.register(12, "brk", 1, sys_brk);
+        table.register(39, "getpid", 0, sys_getpid);
+        table.register(60, "exit", 1, sys_exit);
+        table.register(63, "uname", 1, sys_uname);
+        table.register(228, "clock_gettime", 2, sys_clock_gettime);
+        table.register(231, "exit_group", 1, sys_exit);
+        table.register(257, "openat", 4, sys_openat);
+        table
+    }
+
+    /// Adds a syscall, replacing any previous implementation for the same number
+    fn register(&mut self, nr: u64, name: &'static str, args: usize, f: SyscallFn) {
+        self.entries.insert(nr, (name, args, f));
+    }
+
+    /// Runs the syscall requested by the guest, and stores its result in `rax`
+    fn dispatch(&self, emu: &mut Unicorn<'_, Guest>) {
+        let nr = emu.reg_read(RAX).unwrap();
+        let args = [RDI, RSI, RDX, R10, R8, R9].map(|reg| emu.reg_read(reg).unwrap());
+
+        let ret = match self.entries.get(&nr) {
+            Some(&(name, nargs, f)) => {
+                let ret = f(emu, args).unwrap_or_else(|errno| -i64::from(errno) as u64);
+                let args = args[..nargs].iter().map(|arg| format!("{arg:#x}"));
+                let args = args.collect::<Vec<_>>().join(", ");
+                eprintln!("SYSCALL: {name}({args}) = {}", ret as i64);
+                ret
+            }
+            None => {
+                eprintln!("SYSCALL: unknown syscall {nr} = -ENOSYS");
+                -i64::from(libc::ENOSYS) as u64
+            }
+        };
+        emu.reg_write(RAX, ret).unwrap();
+    }
+}
+
+/// The errno of the last failed libc call
+fn last_errno() -> i32 {
+    io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EIO)
+}
+
+/// Turns the return value of a libc call into a syscall result
+fn host_result(ret: i64) -> Result<u64, i32> {
+    if ret < 0 {
+        Err(last_errno())
+    } else {
+        Ok(ret as u64)
+    }
+}
+
+/// Checks that the guest is allowed to use a host file descriptor
+fn guest_fd(emu: &Unicorn<'_, Guest>, fd: u64) -> Result<i32, i32> {
+    let fd = i32::try_from(fd).map_err(|_| libc::EBADF)?;
+    if (0..=2).contains(&fd) || emu.get_data().fds.contains(&fd) {
+        Ok(fd)
+    } else {
+        Err(libc::EBADF)
+    }
+}
+
+/// Reads a NUL-terminated string from guest memory
+fn read_c_string(emu: &Unicorn<'_, Guest>, addr: u64) -> Result<CString, i32> {
+    let mut bytes = Vec::new();
+    loop {
+        let mut byte = [0];
+        emu.mem_read(addr + bytes.len() as u64, &mut byte)
+            .map_err(|_| libc::EFAULT)?;
+        if byte[0] == 0 {
+            return Ok(CString::new(bytes).unwrap());
+        }
+        if bytes.len() >= libc::PATH_MAX as usize {
+            return Err(libc::ENAMETOOLONG);
+        }
+        bytes.push(byte[0]);
+    }
+}
+
+fn page_align(addr: u64) -> u64 {
+    addr.next_multiple_of(EMU_PAGE_SIZE)
+}
+
+fn sys_read(emu: &mut Unicorn<'_, Guest>, [fd, buf, count, ..]: [u64; 6]) -> Result<u64, i32> {
+    let fd = guest_fd(emu, fd)?;
+    let mut data = vec![0; count.min(1 << 20) as usize];
+    // SAFETY: `data` is valid for writes of its whole length
+    let len = host_result(unsafe { libc::read(fd, data.as_mut_ptr().cast(), data.len()) } as i64)?;
+    emu.mem_write(buf, &data[..len as usize])
+        .map_err(|_| libc::EFAULT)?;
+    Ok(len)
+}
+
+fn sys_write(emu: &mut Unicorn<'_, Guest>, [fd, buf, count, ..]: [u64; 6]) -> Result<u64, i32> {
+    let fd = guest_fd(emu, fd)?;
+    let data = emu
+        .mem_read_as_vec(buf, count.min(1 << 20) as usize)
+        .map_err(|_| libc::EFAULT)?;
+    // SAFETY: `data` is valid for reads of its whole length
+    host_result(unsafe { libc::write(fd, data.as_ptr().cast(), data.len()) } as i64)
+}
+
+fn sys_openat(
+    emu: &mut Unicorn<'_, Guest>,
+    [dirfd, path, flags, mode, ..]: [u64; 6],
+) -> Result<u64, i32> {
+    let dirfd = match dirfd as i32 {
+        libc::AT_FDCWD => libc::AT_FDCWD,
+        _ => guest_fd(emu, dirfd)?,
+    };
+    let path = read_c_string(emu, path)?;
+    // SAFETY: `path` is a valid C string
+    let fd = unsafe { libc::openat(dirfd, path.as_ptr(), flags as i32, mode as libc::c_uint) };
+    let fd = host_result(fd.into())?;
+    emu.get_data_mut().fds.insert(fd as i32);
+    Ok(fd)
+}
+
+fn sys_close(emu: &mut Unicorn<'_, Guest>, [fd, ..]: [u64; 6]) -> Result<u64, i32> {
+    let fd = guest_fd(emu, fd)?;
+    // The loader's own stdio stays open, as it is still needed for tracing
+    if emu.get_data_mut().fds.remove(&fd) {
+        // SAFETY: the guest opened this descriptor, so nothing else uses it
+        host_result(unsafe { libc::close(fd) }.into())?;
+    }
+    Ok(0)
+}
+
+fn sys_brk(emu: &mut Unicorn<'_, Guest>, [addr, ..]: [u64; 6]) -> Result<u64, i32> {
+    let Guest { brk_start, brk, .. } = *emu.get_data();
+    // On failure brk returns the current break rather than an error
+    if addr < brk_start {
+        return Ok(brk);
+    }
+
+    let (old_end, new_end) = (page_align(brk), page_align(addr));
+    let resized = if new_end > old_end {
+        let perms = Permission::READ | Permission::WRITE;
+        emu.mem_map(old_end, (new_end - old_end) as usize, perms)
+    } else if new_end < old_end {
+        emu.mem_unmap(new_end, (old_end - new_end) as usize)
+    } else {
+        Ok(())
+    };
+    if resized.is_err() {
+        return Ok(brk);
+    }
+
+    emu.get_data_mut().brk = addr;
+    Ok(addr)
+}
+
+fn sys_mmap(
+    emu: &mut Unicorn<'_, Guest>,
+    [addr, len, prot, flags, fd, offset]: [u64; 6],
+) -> Result<u64, i32> {
+    let flags = flags as i32;
+    if len == 0 || addr % EMU_PAGE_SIZE != 0 || offset % EMU_PAGE_SIZE != 0 {
+        return Err(libc::EINVAL);
+    }
+    let len = len
+        .checked_next_multiple_of(EMU_PAGE_SIZE)
+        .ok_or(libc::ENOMEM)?;
+    let perms = Permission::from_bits_truncate(prot as u32);
+
+    // Everything that can fail is checked before touching the guest's memory,
+    // so that a failed call leaves it as it was
+    let contents = if flags & libc::MAP_ANONYMOUS == 0 {
+        let fd = guest_fd(emu, fd)?;
+        let mut data = vec![0; len as usize];
+        // SAFETY: `data` is valid for writes of its whole length
+        let read = unsafe { libc::pread(fd, data.as_mut_ptr().cast(), data.len(), offset as _) };
+        data.truncate(host_result(read as i64)? as usize);
+        Some(data)
+    } else {
+        None
+    };
+
+    let fixed = flags & libc::MAP_FIXED != 0;
+    let addr = if fixed {
+        addr
+    } else {
+        emu.get_data().mmap_next.max(MMAP_BASE)
+    };
+    let end = addr
+        .checked_add(len)
+        .filter(|&end| end <= ADDRESS_SPACE_END)
+        .ok_or(if fixed { libc::EINVAL } else { libc::ENOMEM })?;
+
+    if fixed {
+        // A fixed mapping replaces whatever was mapped there
+        for page in (addr..end).step_by(EMU_PAGE_SIZE as usize) {
+            let _ = emu.mem_unmap(page, EMU_PAGE_SIZE as usize);
+        }
+    }
+    emu.mem_map(addr, len as usize, perms)
+        .map_err(|_| libc::ENOMEM)?;
+    if !fixed {
+        emu.get_data_mut().mmap_next = end;
+    }
+    if let Some(data) = contents {
+        emu.mem_write(addr, &data).unwrap();
+    }
+
+    Ok(addr)
+}
+
+fn sys_munmap(emu: &mut Unicorn<'_, Guest>, [addr, len, ..]: [u64; 6]) -> Result<u64, i32> {
+    if len == 0 || addr % EMU_PAGE_SIZE != 0 {
+        return Err(libc::EINVAL);
+    }
+    emu.mem_unmap(addr, page_align(len) as usize)
+        .map_err(|_| libc::EINVAL)?;
+    Ok(0)
+}
+
+fn sys_exit(emu: &mut Unicorn<'_, Guest>, [status, ..]: [u64; 6]) -> Result<u64, i32> {
+    emu.get_data_mut().exit_code = Some(status as i32);
+    emu.emu_stop().unwrap();
+    Ok(0)
+}
+
+fn sys_clock_gettime(emu: &mut Unicorn<'_, Guest>, [clock, tp, ..]: [u64; 6]) -> Result<u64, i32> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid timespec to write to
+    host_result(unsafe { libc::clock_gettime(clock as libc::clockid_t, &mut ts) }.into())?;
+
+    let mut buf = [0; 16];
+    buf[..8].copy_from_slice(&ts.tv_sec.to_le_bytes());
+    buf[8..].copy_from_slice(&ts.tv_nsec.to_le_bytes());
+    emu.mem_write(tp, &buf).map_err(|_| libc::EFAULT)?;
+    Ok(0)
+}
+
+fn sys_getpid(_emu: &mut Unicorn<'_, Guest>, _args: [u64; 6]) -> Result<u64, i32> {
+    // SAFETY: getpid has no preconditions
+    Ok(unsafe { libc::getpid() } as u64)
+}
+
+fn sys_uname(emu: &mut Unicorn<'_, Guest>, [buf, ..]: [u64; 6]) -> Result<u64, i32> {
+    // SAFETY: utsname is plain data, for which all zeroes is a valid value
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    // SAFETY: `uts` is a valid utsname to write to
+    host_result(unsafe { libc::uname(&mut uts) }.into())?;
+
+    // SAFETY: utsname is made only of byte arrays, so it can be viewed as bytes
+    let bytes = unsafe {
+        std::slice::from_raw_parts(
+            (&uts as *const libc::utsname).cast::<u8>(),
+            std::mem::size_of::<libc::utsname>(),
+        )
+    };
+    emu.mem_write(buf, bytes).map_err(|_| libc::EFAULT)?;
+    Ok(0)
+}
+
+/// Runs the program directly on the host CPU.
+///
+/// Segments are mapped at their own address (grouped into regions by
+/// [`plan_regions`]), so this only works for executables whose segments don't
+/// collide with the loader's own mappings. The program is never returned to: it
+/// has to terminate itself with the `exit` syscall.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn run_native(
+    file: &Vsbf,
+    data: &[u8],
+    options: &Options,
+) -> Result<std::convert::Infallible, String> {
+    let segments = file.segments();
+    let regions = plan_regions(&segments, page_size() as u64)?;
+
+    // Map everything writable first, so that the contents can be copied in. The
+    // mappings are anonymous, so the parts not backed by the file (e.g. BSS) are
+    // already zeroed
+    for region in &regions {
+        let len = (region.end - region.start) as usize;
+        map_anonymous(
+            Some(region.start as usize),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+        )
+        .map_err(|e| format!("cannot map segment {}: {e}", region.segment))?;
+    }
+    for (i, segment) in segments.iter().enumerate() {
+        copy_segment(segment, data).map_err(|e| format!("cannot load segment {i}: {e}"))?;
+    }
+    for region in &regions {
+        let len = (region.end - region.start) as usize;
+        // SAFETY: the region was mapped above, and nothing in the loader refers to it
+        if unsafe { libc::mprotect(region.start as _, len, protection(region.flags)) } != 0 {
+            let e = io::Error::last_os_error();
+            return Err(format!("cannot protect segment {}: {e}", region.segment));
+        }
+    }
+
+    let stack_size = options.stack_size as usize;
+    let stack = map_anonymous(None, stack_size, libc::PROT_READ | libc::PROT_WRITE)
+        .map_err(|e| format!("cannot map stack: {e}"))?;
+    let top = stack as u64 + stack_size as u64;
+    let (sp, contents) = initial_stack(top, options, file.entry(), page_size() as u64)
+        .map_err(|e| format!("cannot set up stack: {e}"))?;
+
+    // SAFETY: the segments were mapped at the addresses the program was linked for,
+    // and the stack is ours to hand over: control never comes back to Rust code
+    unsafe {
+        std::ptr::copy_nonoverlapping(contents.as_ptr(), sp as *mut u8, contents.len());
+        std::arch::asm!(
+            "mov rsp, {sp}",
+            "xor ebp, ebp",
+            "jmp {entry}",
+            sp = in(reg) sp,
+            entry = in(reg) file.entry(),
+            // The ABI wants a function pointer to register with `atexit` in rdx, or null
+            in("rdx") 0,
+            options(noreturn),
+        )
+    }
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+fn run_native(
+    _file: &Vsbf,
+    _data: &[u8],
+    _options: &Options,
+) -> Result<std::convert::Infallible, String> {
+    Err("native loading is only supported on x86-64 Linux".to_string())
+}
+
+/// Copies a segment's contents to its address, which must already be mapped writable
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn copy_segment(segment: &SegmentHeader, data: &[u8]) -> Result<(), String> {
+    let contents = (segment.file as usize)
+        .checked_add(segment.file_size as usize)
+        .and_then(|end| data.get(segment.file as usize..end))
+        .ok_or("contents out of file bounds")?;
+    if contents.len() > segment.mem_size as usize {
+        return Err("file size is bigger than memory size".to_string());
+    }
+
+    // SAFETY: `plan_regions` mapped the whole segment
+    unsafe {
+        std::ptr::copy_nonoverlapping(contents.as_ptr(), segment.mem as *mut u8, contents.len());
+    }
+    Ok(())
+}
+
+/// Maps zeroed memory, either at exactly `addr` (failing if it is already in use)
+/// or wherever the kernel sees fit
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn map_anonymous(addr: Option<usize>, len: usize, prot: i32) -> Result<*mut libc::c_void, String> {
+    let mut flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+    if addr.is_some() {
+        flags |= libc::MAP_FIXED_NOREPLACE;
+    }
+    let hint = addr.unwrap_or(0) as *mut libc::c_void;
+
+    // SAFETY: with MAP_FIXED_NOREPLACE no existing mapping can be clobbered
+    let ptr = unsafe { libc::mmap(hint, len, prot, flags, -1, 0) };
+    if ptr == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+    // Kernels older than 4.17 ignore MAP_FIXED_NOREPLACE and treat it as a hint
+    if addr.is_some_and(|addr| addr != ptr as usize) {
+        // SAFETY: the mapping was just created and nothing refers to it
+        unsafe { libc::munmap(ptr, len) };
+        return Err("address already in use".to_string());
+    }
+
+    Ok(ptr)
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Translates segment permissions to the `PROT_*` flags of `mmap`
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn protection(flags: PermissionFlags) -> i32 {
+    let mut prot = libc::PROT_NONE;
+    if flags.contains(PermissionFlags::R) {
+        prot |= libc::PROT_READ;
+    }
+    if flags.contains(PermissionFlags::W) {
+        prot |= libc::PROT_WRITE;
+    }
+    if flags.contains(PermissionFlags::X) {
+        prot |= libc::PROT_EXEC;
+    }
+    prot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(mem: u64, mem_size: u32, flags: PermissionFlags) -> SegmentHeader {
+        SegmentHeader {
+            typ: 0,
+            flags,
+            align: 0x1000,
+            file: 0,
+            mem,
+            file_size: 0,
+            mem_size,
+        }
+    }
+
+    fn args(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    fn guest() -> Unicorn<'static, Guest> {
+        Unicorn::new_with_data(Arch::X86, Mode::MODE_64, Guest::default()).unwrap()
+    }
+
+    /// Runs a syscall through the Linux table, as if the guest executed `syscall`
+    fn syscall(emu: &mut Unicorn<'_, Guest>, nr: u64, args: [u64; 6]) -> i64 {
+        emu.reg_write(RAX, nr).unwrap();
+        for (reg, arg) in [RDI, RSI, RDX, R10, R8, R9].into_iter().zip(args) {
+            emu.reg_write(reg, arg).unwrap();
+        }
+        SyscallTable::linux().dispatch(emu);
+        emu.reg_read(RAX).unwrap() as i64
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let opts = args(&[
+            "--clear-env",
+            "prog",
+            "--env",
+            "A=1",
+            "--env",
+            "B=2",
+            "--env",
+            "A=3",
+            "--",
+            "x",
+            "--native",
+        ])
+        .unwrap();
+        assert!(!opts.native);
+        assert_eq!(opts.path, "prog");
+        assert_eq!(opts.argv, [c"prog", c"x", c"--native"]);
+        assert_eq!(opts.envp, [c"B=2", c"A=3"]);
+        assert_eq!(opts.stack_size, DEFAULT_STACK_SIZE);
+        assert_eq!(opts.insn_limit, 0);
+
+        // The loader's environment is passed through, with overrides
+        let opts = args(&["prog", "--env", "PATH=/nowhere"]).unwrap();
+        let paths: Vec<_> = opts
+            .envp
+            .iter()
+            .filter(|var| var.to_bytes().starts_with(b"PATH="))
+            .collect();
+        assert_eq!(paths, [c"PATH=/nowhere"]);
+
+        let opts = args(&["--native", "--stack-size", "0x2000", "prog"]).unwrap();
+        assert!(opts.native);
+        assert_eq!(opts.stack_size, 0x2000);
+        assert_eq!(opts.argv, [c"prog"]);
+
+        assert!(args(&["prog", "--env", "A"]).is_err());
+        assert!(args(&["prog", "--stack-size", "0"]).is_err());
+        assert!(args(&["prog", "--stack-size", "0x8001000"]).is_err());
+        assert!(args(&["prog", "extra"]).is_err());
+        assert!(args(&["--", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_initial_stack() {
+        let opts = args(&["--clear-env", "prog", "--env", "K=V", "--", "arg"]).unwrap();
+        let top = 0x8000000;
+        let (sp, stack) = initial_stack(top, &opts, 0x401000, 0x1000).unwrap();
+
+        assert_eq!(sp % 16, 0);
+        assert_eq!(stack.len() as u64, top - sp);
+
+        let word = |i: usize| u64::from_le_bytes(stack[i * 8..i * 8 + 8].try_into().unwrap());
+        let string = |addr: u64| {
+            let start = (addr - sp) as usize;
+            let len = stack[start..].iter().position(|&b| b == 0).unwrap();
+            std::str::from_utf8(&stack[start..start + len]).unwrap()
+        };
+
+        // argc, argv, envp
+        assert_eq!(word(0), 2);
+        assert_eq!(string(word(1)), "prog");
+        assert_eq!(string(word(2)), "arg");
+        assert_eq!(word(3), 0);
+        assert_eq!(string(word(4)), "K=V");
+        assert_eq!(word(5), 0);
+
+        // auxv
+        assert_eq!((word(6), word(7)), (AT_PAGESZ, 0x1000));
+        assert_eq!((word(8), word(9)), (AT_ENTRY, 0x401000));
+        assert_eq!(word(10), AT_RANDOM);
+        assert!((sp..top - 16).contains(&word(11)));
+        assert_eq!((word(12), word(13)), (AT_NULL, 0));
+
+        let tiny = Options {
+            stack_size: 0x20,
+            ..opts
+        };
+        assert!(initial_stack(top, &tiny, 0x401000, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_plan_regions() {
+        let rw = PermissionFlags::R | PermissionFlags::W;
+        let rx = PermissionFlags::R | PermissionFlags::X;
+
+        // `.data` and `.init_array` share a page, `.text` gets its own
+        let segments = [
+            segment(0x400008, 8, rw),
+            segment(0x401000, 0x1800, rx),
+            segment(0x400000, 5, rw),
+        ];
+        let regions = plan_regions(&segments, 0x1000).unwrap();
+        let ranges: Vec<_> = regions
+            .iter()
+            .map(|r| (r.start, r.end, r.flags, r.segment))
+            .collect();
+        assert_eq!(
+            ranges,
+            [(0x400000, 0x401000, rw, 0), (0x401000, 0x403000, rx, 1)]
+        );
+
+        // A page can't be both writable and executable
+        let segments = [segment(0x400000, 0x10, rx), segment(0x400010, 8, rw)];
+        assert_eq!(
+            plan_regions(&segments, 0x1000).err().unwrap(),
+            "segment 1 (RW ) shares pages with segment 0 (R X)"
+        );
+
+        let mut bad_align = segment(0x400000, 8, rw);
+        bad_align.align = 0x1800;
+        assert!(plan_regions(&[bad_align], 0x1000).is_err());
+        assert!(plan_regions(&[segment(u64::MAX - 4, 8, rw)], 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_emu_stack_bottom() {
+        let rw = PermissionFlags::R | PermissionFlags::W;
+        let below = plan_regions(&[segment(0x400000, 0x1000, rw)], 0x1000).unwrap();
+        let above = plan_regions(&[segment(0x10000000, 0x1000, rw)], 0x1000).unwrap();
+
+        assert_eq!(
+            emu_stack_bottom(&below, 0x10000),
+            Ok(EMU_STACK_TOP - 0x10000)
+        );
+        // Segments above the stack don't get in the way
+        assert_eq!(
+            emu_stack_bottom(&above, 0x10000),
+            Ok(EMU_STACK_TOP - 0x10000)
+        );
+        assert_eq!(
+            emu_stack_bottom(&below, EMU_STACK_TOP),
+            Err("a stack of 0x8000000 bytes would overlap segment 0".to_string())
+        );
+        assert_eq!(emu_stack_bottom(&above, EMU_STACK_TOP), Ok(0));
+    }
+
+    #[test]
+    fn test_syscall_unknown() {
+        let mut emu = guest();
+        assert_eq!(syscall(&mut emu, 999, [0; 6]), -i64::from(libc::ENOSYS));
+    }
+
+    #[test]
+    fn test_syscall_brk() {
+        let mut emu = guest();
+        emu.get_data_mut().brk_start = 0x600000;
+        emu.get_data_mut().brk = 0x600000;
+
+        // Grow: the new heap is mapped and writable
+        assert_eq!(syscall(&mut emu, 12, [0x601800, 0, 0, 0, 0, 0]), 0x601800);
+        assert_eq!(emu.get_data().brk, 0x601800);
+        emu.mem_write(0x601fff, &[1]).unwrap();
+
+        // Moving below the initial break fails, returning the current one
+        assert_eq!(syscall(&mut emu, 12, [0, 0, 0, 0, 0, 0]), 0x601800);
+
+        // Shrink: the pages above the new break are unmapped
+        assert_eq!(syscall(&mut emu, 12, [0x601000, 0, 0, 0, 0, 0]), 0x601000);
+        emu.mem_write(0x600fff, &[1]).unwrap();
+        assert!(emu.mem_write(0x601000, &[1]).is_err());
+    }
+
+    #[test]
+    fn test_syscall_mmap() {
+        let mut emu = guest();
+        let prot = (libc::PROT_READ | libc::PROT_WRITE) as u64;
+        let anon = (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64;
+
+        let addr = syscall(&mut emu, 9, [0, 0x1800, prot, anon, u64::MAX, 0]);
+        assert_eq!(addr as u64, MMAP_BASE);
+        assert_eq!(emu.get_data().mmap_next, MMAP_BASE + 0x2000);
+        emu.mem_write(MMAP_BASE + 0x1fff, &[1]).unwrap();
+
+        let addr = syscall(&mut emu, 9, [0, 0x1000, prot, anon, u64::MAX, 0]);
+        assert_eq!(addr as u64, MMAP_BASE + 0x2000);
+
+        assert_eq!(syscall(&mut emu, 11, [MMAP_BASE, 0x2000, 0, 0, 0, 0]), 0);
+        assert!(emu.mem_write(MMAP_BASE, &[1]).is_err());
+        emu.mem_write(MMAP_BASE + 0x2000, &[1]).unwrap();
+        assert_eq!(
+            syscall(&mut emu, 11, [MMAP_BASE + 1, 0x1000, 0, 0, 0, 0]),
+            -i64::from(libc::EINVAL)
+        );
+    }
+
+    #[test]
+    fn test_syscall_mmap_failure() {
+        let mut emu = guest();
+        let prot = (libc::PROT_READ | libc::PROT_WRITE) as u64;
+        let anon = (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64;
+        syscall(&mut emu, 9, [0, 0x1000, prot, anon, u64::MAX, 0]);
+        emu.mem_write(MMAP_BASE, b"data").unwrap();
+
+        // Mapping a file the guest didn't open
+        let private = libc::MAP_PRIVATE as u64;
+        assert_eq!(
+            syscall(&mut emu, 9, [0, 0x1000, prot, private, 100, 0]),
+            -i64::from(libc::EBADF)
+        );
+        // A fixed mapping over the existing one, running past the address space
+        let fixed = anon | libc::MAP_FIXED as u64;
+        assert_eq!(
+            syscall(
+                &mut emu,
+                9,
+                [MMAP_BASE, u64::MAX - 0xfff, prot, fixed, 0, 0]
+            ),
+            -i64::from(libc::EINVAL)
+        );
+
+        assert_eq!(emu.get_data().mmap_next, MMAP_BASE + 0x1000);
+        assert_eq!(emu.mem_read_as_vec(MMAP_BASE, 4).unwrap(), b"data");
     }
 }
@@ -1,13 +1,23 @@
-use std::env::args;
+use std::{env::args, process::exit};
 
-use vsbf::{FileHeader, Vsbf};
+use vsbf::{FileHeader, Sym, Vsbf};
 
 fn main() {
     let buf = std::fs::read(args().nth(1).unwrap()).unwrap();
-    let (_data, file) = Vsbf::parse(&buf).unwrap();
+    let (_data, file) = match Vsbf::parse(&buf) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("error: {e}");
+            exit(1);
+        }
+    };
 
     let (_, hdr) = FileHeader::parse(&buf).expect("Failed to parse header");
     println!("File header: {hdr:?}");
+    if let Some((sym, offset)) = file.symbol_at_address(file.entry()) {
+        let name = file.string_at(sym.name).unwrap_or("<invalid>");
+        println!("Entry point: 0x{:x} <{name}+0x{offset:x}>", file.entry());
+    }
 
     println!("\nSegments:");
     for segm in file.segments() {
@@ -16,6 +26,19 @@ fn main() {
 
     println!("\nSections:");
     for sect in file.sections() {
-        println!("- {sect:x?}");
+        let name = file.section_name(&sect).unwrap_or("<invalid>");
+        println!("- {name}: {sect:x?}");
+    }
+
+    println!("\nSymbols:");
+    Sym::print(&file, file.syms());
+
+    println!("\nValidation:");
+    let violations = file.validate();
+    if violations.is_empty() {
+        println!("- no problems found");
+    }
+    for violation in violations {
+        println!("- {violation}");
     }
 }
@@ -1,11 +1,325 @@
+//! Reading and writing VSBF files.
+//!
+//! # Format versions
+//!
+//! Version 0 files start with the `VSBF` magic and have no version field.
+//! Later versions start with `VSBV`, followed by the version as a `u16`.
+//!
+//! The version is bumped for every change that older readers can't safely
+//! skip over. Readers accept every version up to [`FORMAT_VERSION`] and reject
+//! newer ones with [`VsbfError::UnsupportedVersion`]; writers always write
+//! the current version.
+//!
+//! Since version 2, the file header can also grow without a version bump:
+//! - the header starts with its own size, so new optional fields can be
+//!   appended to it. Readers skip the trailing fields they don't know, and
+//!   reject headers too short to hold every field of their version with
+//!   [`VsbfError::Truncated`];
+//! - the low byte of the header [flags](FileFlags) holds optional features,
+//!   which readers ignore when they don't know them. The high byte holds
+//!   required features: readers reject files using a required feature they
+//!   don't know with [`VsbfError::UnsupportedFlags`].
+
 use core::{fmt, str};
-use std::{fmt::Display, io};
+use std::{collections::HashMap, error::Error, fmt::Display, io, sync::OnceLock};
 
 use bitflags::bitflags;
-use nom::{bytes::complete as bytes, multi, number::complete as number, IResult};
+use nom::{bytes::complete as bytes, number::complete as number, IResult};
+
+/// Everything that can go wrong when reading a VSBF file.
+///
+/// Offsets are in bytes from the start of the file, except for string table
+/// offsets, which are relative to the start of the string table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VsbfError {
+    /// The file doesn't start with a VSBF magic
+    BadMagic,
+    /// The file was written in a format version this crate doesn't understand
+    UnsupportedVersion(u16),
+    /// The file uses required features this crate doesn't understand
+    UnsupportedFlags(u16),
+    /// The file ends in the middle of a record
+    Truncated { what: &'static str, offset: usize },
+    /// A section header has a type this crate doesn't know about
+    UnknownSectionType { offset: usize, typ: u8 },
+    /// A relocation has a type this crate doesn't know about
+    UnknownRelocationType { offset: usize, typ: u16 },
+    /// A symbol has a binding this crate doesn't know about
+    UnknownSymbolBinding { offset: usize, binding: u8 },
+    /// A symbol has a kind this crate doesn't know about
+    UnknownSymbolKind { offset: usize, kind: u8 },
+    /// A string table offset points outside of the string table
+    StringOutOfRange { offset: u32 },
+    /// A string in the string table is not valid UTF-8
+    InvalidUtf8 { offset: u32 },
+    /// The header declares more records than the file can contain
+    CountOverflow { what: &'static str, count: u64 },
+    /// An archive symbol index entry refers to a member that doesn't exist
+    MemberOutOfRange { offset: usize, member: u32 },
+}
+impl VsbfError {
+    /// Shift the error's file offset by `base`, for errors found while
+    /// parsing a record that starts `base` bytes into the file
+    fn offset_by(self, base: usize) -> Self {
+        use VsbfError::*;
+        match self {
+            Truncated { what, offset } => Truncated {
+                what,
+                offset: offset + base,
+            },
+            UnknownSectionType { offset, typ } => UnknownSectionType {
+                offset: offset + base,
+                typ,
+            },
+            UnknownRelocationType { offset, typ } => UnknownRelocationType {
+                offset: offset + base,
+                typ,
+            },
+            UnknownSymbolBinding { offset, binding } => UnknownSymbolBinding {
+                offset: offset + base,
+                binding,
+            },
+            UnknownSymbolKind { offset, kind } => UnknownSymbolKind {
+                offset: offset + base,
+                kind,
+            },
+            e => e,
+        }
+    }
+}
+impl fmt::Display for VsbfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use VsbfError::*;
+        match self {
+            BadMagic => write!(f, "not a VSBF file (bad magic)"),
+            UnsupportedVersion(version) => write!(f, "unsupported format version {version}"),
+            UnsupportedFlags(flags) => write!(f, "unsupported required features 0x{flags:x}"),
+            Truncated { what, offset } => write!(f, "truncated {what} at offset 0x{offset:x}"),
+            UnknownSectionType { offset, typ } => {
+                write!(f, "unknown section type {typ} at offset 0x{offset:x}")
+            }
+            UnknownRelocationType { offset, typ } => {
+                write!(f, "unknown relocation type {typ} at offset 0x{offset:x}")
+            }
+            UnknownSymbolBinding { offset, binding } => {
+                write!(f, "unknown symbol binding {binding} at offset 0x{offset:x}")
+            }
+            UnknownSymbolKind { offset, kind } => {
+                write!(f, "unknown symbol kind {kind} at offset 0x{offset:x}")
+            }
+            StringOutOfRange { offset } => {
+                write!(f, "string table offset 0x{offset:x} is out of range")
+            }
+            InvalidUtf8 { offset } => {
+                write!(
+                    f,
+                    "string at string table offset 0x{offset:x} is not valid UTF-8"
+                )
+            }
+            CountOverflow { what, count } => {
+                write!(
+                    f,
+                    "header declares {count} {what}s, more than the file can contain"
+                )
+            }
+            MemberOutOfRange { offset, member } => {
+                write!(
+                    f,
+                    "archive symbol at offset 0x{offset:x} refers to missing member {member}"
+                )
+            }
+        }
+    }
+}
+impl Error for VsbfError {}
+
+/// A consistency problem found by [`Vsbf::validate`].
+///
+/// Records are identified by their index in the file; offsets are relative
+/// to the start of the data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// A section's bytes extend past the end of the data
+    SectionOutOfBounds { section: usize, end: u64 },
+    /// A section's name can't be read from the string table
+    BadSectionName { section: usize, error: VsbfError },
+    /// A segment's bytes extend past the end of the data
+    SegmentOutOfBounds { segment: usize, end: u64 },
+    /// Two segments occupy some of the same memory
+    SegmentsOverlap { first: usize, second: usize },
+    /// A symbol refers to a section that doesn't exist
+    NoSuchSection { symbol: usize, section: u16 },
+    /// A symbol extends past the end of its section
+    SymbolOutOfSection { symbol: usize, section: u16 },
+    /// A symbol's name can't be read from the string table
+    BadSymbolName { symbol: usize, error: VsbfError },
+    /// A relocation's target bytes are not all inside a single section
+    RelocationOutOfSection { relocation: usize, offset: u64 },
+    /// A relocation's symbol name can't be read from the string table
+    BadRelocationName { relocation: usize, error: VsbfError },
+}
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Violation::*;
+        match self {
+            SectionOutOfBounds { section, end } => {
+                write!(
+                    f,
+                    "section {section} ends at 0x{end:x}, past the end of the data"
+                )
+            }
+            BadSectionName { section, error } => write!(f, "section {section}: {error}"),
+            SegmentOutOfBounds { segment, end } => {
+                write!(
+                    f,
+                    "segment {segment} ends at 0x{end:x}, past the end of the data"
+                )
+            }
+            SegmentsOverlap { first, second } => {
+                write!(f, "segments {first} and {second} overlap in memory")
+            }
+            NoSuchSection { symbol, section } => {
+                write!(f, "symbol {symbol} refers to missing section {section}")
+            }
+            SymbolOutOfSection { symbol, section } => {
+                write!(
+                    f,
+                    "symbol {symbol} extends past the end of section {section}"
+                )
+            }
+            BadSymbolName { symbol, error } => write!(f, "symbol {symbol}: {error}"),
+            RelocationOutOfSection { relocation, offset } => write!(
+                f,
+                "relocation {relocation} at 0x{offset:x} is not inside a single section"
+            ),
+            BadRelocationName { relocation, error } => {
+                write!(f, "relocation {relocation}: {error}")
+            }
+        }
+    }
+}
+
+/// Turn a nom error into a [`VsbfError::Truncated`] for a record starting at offset 0
+fn truncated<E>(what: &'static str) -> impl FnOnce(nom::Err<E>) -> VsbfError {
+    move |_| VsbfError::Truncated { what, offset: 0 }
+}
+
+/// Parse `count` consecutive records, reporting errors at their offset in `file`
+fn count<'a, T>(
+    file: &[u8],
+    mut i: &'a [u8],
+    what: &'static str,
+    size: u32,
+    count: usize,
+    parse: impl Fn(&'a [u8]) -> Result<(&'a [u8], T), VsbfError>,
+) -> Result<(&'a [u8], Vec<T>), VsbfError> {
+    if count.saturating_mul(size as usize) > i.len() {
+        let count = count as u64;
+        return Err(VsbfError::CountOverflow { what, count });
+    }
+
+    let mut ret = Vec::with_capacity(count);
+    for _ in 0..count {
+        let base = file.len() - i.len();
+        let (rest, item) = parse(i).map_err(|e| e.offset_by(base))?;
+        ret.push(item);
+        i = rest;
+    }
+
+    Ok((i, ret))
+}
+
+/// Read the length-prefixed string at `offset` in a string table
+fn string_at(strtab: &[u8], offset: u32) -> Result<&str, VsbfError> {
+    let i = offset as usize;
+    let out_of_range = VsbfError::StringOutOfRange { offset };
+
+    let len = strtab.get(i..i + 2).ok_or(out_of_range)?;
+    let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+    let data = strtab.get(i + 2..i + 2 + len).ok_or(out_of_range)?;
+
+    str::from_utf8(data).map_err(|_| VsbfError::InvalidUtf8 { offset })
+}
+
+/// Append a length-prefixed string to a string table, returning its offset
+fn push_string(strtab: &mut Vec<u8>, data: &str) -> u32 {
+    assert!(data.len() <= u16::MAX as usize);
+
+    let offset = strtab.len() as u32;
+    strtab.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    strtab.extend_from_slice(data.as_bytes());
+    offset
+}
+
+/// Builds a string table in which every distinct string is stored once.
+///
+/// Strings are length-prefixed, so a string can't share its bytes with the
+/// end of a longer one: only identical strings are merged.
+#[derive(Clone, Debug, Default)]
+pub struct StrTabBuilder {
+    data: Vec<u8>,
+    offsets: HashMap<String, u32>,
+}
+impl StrTabBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a string to the table, returning its offset. Adding a string that
+    /// is already in the table returns the offset of the existing copy.
+    pub fn add(&mut self, data: &str) -> u32 {
+        if let Some(&offset) = self.offsets.get(data) {
+            return offset;
+        }
+
+        let offset = push_string(&mut self.data, data);
+        self.offsets.insert(data.to_string(), offset);
+        offset
+    }
+
+    /// Size of the table built so far, in bytes
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Get the table's bytes, as stored in a file
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Magic of files written before the format was versioned, read as version 0
+pub const MAGIC_V0: [u8; 4] = *b"VSBF";
+/// Magic of versioned files, followed by the format version
+pub const MAGIC: [u8; 4] = *b"VSBV";
+/// Format version written by this crate
+pub const FORMAT_VERSION: u16 = 5;
+
+bitflags! {
+    /// Features used by a file. Bits in [`FileFlags::REQUIRED`] must be
+    /// understood to read the file, the others can be ignored.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct FileFlags: u16 {
+        /// The file is a linked executable rather than a relocatable object
+        const EXEC = 0b1;
+    }
+}
+impl FileFlags {
+    pub const REQUIRED: u16 = 0xff00;
+}
 
+pub const FILE_HDR_SIZE: u32 = 46;
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct FileHeader {
+    /// Format version the file was written in. Headers are always written
+    /// with [`FORMAT_VERSION`], regardless of this field.
+    pub version: u16,
+    /// Features used by the file (since version 2)
+    pub flags: FileFlags,
     pub arch: u16,
     pub os: u16,
     pub num_segments: u16,
@@ -13,11 +327,66 @@ pub struct FileHeader {
     pub strtab_size: u32,
     pub num_symbols: u32,
     pub num_relocs: u32,
+    /// Address execution starts from (since version 1)
+    pub entry: u64,
     pub next_header: u64,
 }
 impl FileHeader {
-    pub fn parse(i: &[u8]) -> IResult<&[u8], FileHeader> {
-        let (i, _) = bytes::tag("VSBF")(i)?;
+    pub fn parse(file: &[u8]) -> Result<(&[u8], FileHeader), VsbfError> {
+        let (i, magic) = bytes::take::<_, _, ()>(4usize)(file).map_err(truncated("file header"))?;
+
+        let (i, version) = match magic {
+            m if m == MAGIC_V0 => (i, 0),
+            m if m == MAGIC => number::le_u16::<_, ()>(i).map_err(truncated("file header"))?,
+            _ => return Err(VsbfError::BadMagic),
+        };
+
+        let (i, header_size, flags) = match version {
+            // Versions before 2 have a fixed header layout
+            0 => (i, 32, FileFlags::empty()),
+            1 => (i, 42, FileFlags::empty()),
+            2..=5 => {
+                let (i, (header_size, flags)) =
+                    Self::parse_v2_prefix(i).map_err(truncated("file header"))?;
+
+                let unknown = flags & !FileFlags::all().bits();
+                if unknown & FileFlags::REQUIRED != 0 {
+                    return Err(VsbfError::UnsupportedFlags(unknown & FileFlags::REQUIRED));
+                }
+
+                (
+                    i,
+                    header_size as usize,
+                    FileFlags::from_bits_truncate(flags),
+                )
+            }
+            _ => return Err(VsbfError::UnsupportedVersion(version)),
+        };
+
+        let (i, mut ret) = Self::parse_fields(i, version).map_err(truncated("file header"))?;
+        ret.flags = flags;
+
+        // Skip optional fields added after this crate was written
+        let known = file.len() - i.len();
+        let i = header_size
+            .checked_sub(known)
+            .and_then(|unknown| i.get(unknown..))
+            .ok_or(VsbfError::Truncated {
+                what: "file header",
+                offset: 0,
+            })?;
+
+        Ok((i, ret))
+    }
+
+    fn parse_v2_prefix(i: &[u8]) -> IResult<&[u8], (u16, u16)> {
+        let (i, header_size) = number::le_u16(i)?;
+        let (i, flags) = number::le_u16(i)?;
+
+        Ok((i, (header_size, flags)))
+    }
+
+    fn parse_fields(i: &[u8], version: u16) -> IResult<&[u8], FileHeader> {
         let (i, arch) = number::le_u16(i)?;
         let (i, os) = number::le_u16(i)?;
         let (i, num_segments) = number::le_u16(i)?;
@@ -25,9 +394,15 @@ impl FileHeader {
         let (i, strtab_size) = number::le_u32(i)?;
         let (i, num_symbols) = number::le_u32(i)?;
         let (i, num_relocs) = number::le_u32(i)?;
+        let (i, entry) = match version {
+            0 => (i, 0),
+            _ => number::le_u64(i)?,
+        };
         let (i, next_header) = number::le_u64(i)?;
 
         let ret = FileHeader {
+            version,
+            flags: FileFlags::empty(),
             arch,
             os,
             num_segments,
@@ -36,13 +411,17 @@ impl FileHeader {
             strtab_size,
             num_symbols,
             num_relocs,
+            entry,
         };
 
         Ok((i, ret))
     }
 
     pub fn write(&self, w: &mut dyn io::Write) -> io::Result<()> {
-        w.write_all(b"VSBF")?;
+        w.write_all(&MAGIC)?;
+        w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        w.write_all(&(FILE_HDR_SIZE as u16).to_le_bytes())?;
+        w.write_all(&self.flags.bits().to_le_bytes())?;
         w.write_all(&self.arch.to_le_bytes())?;
         w.write_all(&self.os.to_le_bytes())?;
         w.write_all(&self.num_segments.to_le_bytes())?;
@@ -50,15 +429,19 @@ impl FileHeader {
         w.write_all(&self.strtab_size.to_le_bytes())?;
         w.write_all(&self.num_symbols.to_le_bytes())?;
         w.write_all(&self.num_relocs.to_le_bytes())?;
+        w.write_all(&self.entry.to_le_bytes())?;
         w.write_all(&self.next_header.to_le_bytes())?;
         Ok(())
     }
 
     pub fn print(&self) {
+        println!("Format version: {}", self.version);
+        println!("Flags: {:?}", self.flags);
         println!("Architecture: {}", self.arch);
         println!("OS/ABI: {}", self.os);
         println!("Number of segment headers: {}", self.num_segments);
         println!("Number of section headers: {}", self.num_sections);
+        println!("Entry point: 0x{:x}", self.entry);
         println!("Next file header: {}", self.next_header);
     }
 }
@@ -66,75 +449,259 @@ impl FileHeader {
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Sym {
     pub name: u32, // offset into strtab
-    pub size: u16,
+    /// Size of the symbol (since version 5, 16 bits before)
+    pub size: u32,
+    /// Index of the section the symbol is in, or one of the reserved indices
+    /// ([`SECTION_UNDEF`], [`SECTION_ABS`] or [`SECTION_COMMON`])
     pub section: u16,
     pub value: u64,
+    /// Visibility to other objects (since version 3, global before)
+    pub binding: SymbolBinding,
+    /// What the symbol refers to (since version 3, none before)
+    pub kind: SymbolKind,
 }
+pub const SYM_SIZE: u32 = 20;
+
+/// Section indices from here on don't refer to an actual section
+pub const SECTION_RESERVED: u16 = 0xff00;
+/// Section index of symbols that are referenced, but defined in another object
+pub const SECTION_UNDEF: u16 = 0xffff;
+/// Section index of symbols whose value is an absolute address or constant
+pub const SECTION_ABS: u16 = 0xfffe;
+/// Section index of uninitialized data, allocated by the linker. The symbol's
+/// `size` is the size to allocate, and its `value` the required alignment.
+pub const SECTION_COMMON: u16 = 0xfffd;
+
+/// Displays a section index, with reserved indices shown by name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SectionIndex(pub u16);
+impl fmt::Display for SectionIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            SECTION_UNDEF => f.pad("UNDEF"),
+            SECTION_ABS => f.pad("ABS"),
+            SECTION_COMMON => f.pad("COMMON"),
+            idx => f.pad(&idx.to_string()),
+        }
+    }
+}
+
 impl Sym {
-    pub fn parse(i: &[u8]) -> IResult<&[u8], Self> {
-        let (i, name) = number::le_u32(i)?;
-        let (i, size) = number::le_u16(i)?;
-        let (i, section) = number::le_u16(i)?;
-        let (i, value) = number::le_u64(i)?;
+    /// Size of a symbol record in the given format version
+    pub fn size(version: u16) -> u32 {
+        match version {
+            0..=2 => 16,
+            3..=4 => 18,
+            _ => SYM_SIZE,
+        }
+    }
+
+    pub fn parse(i: &[u8], version: u16) -> Result<(&[u8], Self), VsbfError> {
+        let (i, (name, size, section, value)) =
+            Self::parse_fields(i, version).map_err(truncated("symbol"))?;
+
+        let (i, binding, kind) = match version {
+            0..=2 => (i, SymbolBinding::Global, SymbolKind::None),
+            _ => {
+                let (i, (binding, kind)) =
+                    Self::parse_attributes(i).map_err(truncated("symbol"))?;
+                let binding = SymbolBinding::try_from(binding)
+                    .map_err(|binding| VsbfError::UnknownSymbolBinding { offset: 0, binding })?;
+                let kind = SymbolKind::try_from(kind)
+                    .map_err(|kind| VsbfError::UnknownSymbolKind { offset: 0, kind })?;
+                (i, binding, kind)
+            }
+        };
 
         let ret = Self {
             name,
             size,
             section,
             value,
+            binding,
+            kind,
         };
 
         Ok((i, ret))
     }
 
+    fn parse_fields(i: &[u8], version: u16) -> IResult<&[u8], (u32, u32, u16, u64)> {
+        let (i, name) = number::le_u32(i)?;
+        let (i, size) = match version {
+            0..=4 => number::le_u16(i).map(|(i, size)| (i, size as u32))?,
+            _ => number::le_u32(i)?,
+        };
+        let (i, section) = number::le_u16(i)?;
+        let (i, value) = number::le_u64(i)?;
+
+        Ok((i, (name, size, section, value)))
+    }
+
+    fn parse_attributes(i: &[u8]) -> IResult<&[u8], (u8, u8)> {
+        let (i, binding) = number::le_u8(i)?;
+        let (i, kind) = number::le_u8(i)?;
+
+        Ok((i, (binding, kind)))
+    }
+
     pub fn write(&self, w: &mut dyn io::Write) -> io::Result<()> {
         w.write_all(&self.name.to_le_bytes())?;
         w.write_all(&self.size.to_le_bytes())?;
         w.write_all(&self.section.to_le_bytes())?;
         w.write_all(&self.value.to_le_bytes())?;
+        w.write_all(&self.binding.to_le_bytes())?;
+        w.write_all(&self.kind.to_le_bytes())?;
         Ok(())
     }
 
     pub fn print(obj: &Vsbf, syms: &[Self]) {
         let mut name_len = 4;
         for sym in syms {
-            name_len = obj.string_at(sym.name).len().max(name_len);
+            name_len = obj.display_string_at(sym.name).len().max(name_len);
         }
 
         println!(
-            "{:<name_len$} {:6} {:8} {}",
+            "{:<name_len$} {:6} {:10} {:6} {:7} Section",
             "Name",
             "Size",
             "Value",
-            "Section",
+            "Bind",
+            "Type",
             name_len = name_len,
         );
         for sym in syms {
             println!(
-                "{:<name_len$} 0x{:4x} 0x{:08x} {}",
-                obj.string_at(sym.name),
+                "{:<name_len$} 0x{:4x} 0x{:08x} {:6} {:7} {}",
+                obj.display_string_at(sym.name),
                 sym.size,
                 sym.value,
-                sym.section,
+                sym.binding,
+                sym.kind,
+                SectionIndex(sym.section),
                 name_len = name_len,
             );
         }
     }
+
+    pub fn is_undefined(&self) -> bool {
+        self.section == SECTION_UNDEF
+    }
+    pub fn is_absolute(&self) -> bool {
+        self.section == SECTION_ABS
+    }
+    pub fn is_common(&self) -> bool {
+        self.section == SECTION_COMMON
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.binding == SymbolBinding::Local
+    }
+    pub fn is_weak(&self) -> bool {
+        self.binding == SymbolBinding::Weak
+    }
+}
+
+/// Whether a symbol can be referenced from other objects
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SymbolBinding {
+    /// Only visible inside the object defining it
+    Local = 0,
+    /// Visible to every object, and must be defined only once
+    Global = 1,
+    /// Visible to every object, but can be overridden by a global definition
+    Weak = 2,
+}
+impl TryFrom<u8> for SymbolBinding {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use SymbolBinding::*;
+        match value {
+            0 => Ok(Local),
+            1 => Ok(Global),
+            2 => Ok(Weak),
+            _ => Err(value),
+        }
+    }
+}
+impl fmt::Display for SymbolBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            SymbolBinding::Local => "local",
+            SymbolBinding::Global => "global",
+            SymbolBinding::Weak => "weak",
+        })
+    }
+}
+impl SymbolBinding {
+    pub fn to_le_bytes(&self) -> [u8; 1] {
+        [*self as u8]
+    }
+}
+
+/// What kind of entity a symbol refers to
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    /// Unspecified
+    None = 0,
+    /// A data object, like a variable or an array
+    Object = 1,
+    /// A function or other executable code
+    Func = 2,
+    /// A section, for relocations relative to its start
+    Section = 3,
+    /// The source file the object was compiled from
+    File = 4,
+}
+impl TryFrom<u8> for SymbolKind {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use SymbolKind::*;
+        match value {
+            0 => Ok(None),
+            1 => Ok(Object),
+            2 => Ok(Func),
+            3 => Ok(Section),
+            4 => Ok(File),
+            _ => Err(value),
+        }
+    }
+}
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            SymbolKind::None => "none",
+            SymbolKind::Object => "object",
+            SymbolKind::Func => "func",
+            SymbolKind::Section => "section",
+            SymbolKind::File => "file",
+        })
+    }
+}
+impl SymbolKind {
+    pub fn to_le_bytes(&self) -> [u8; 1] {
+        [*self as u8]
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Rel {
-    pub typ: u16,
+    pub typ: RelocationType,
     pub addend: i16,
     pub needed: u32, // offset into strtab
     pub offset: u64,
 }
+pub const REL_SIZE: u32 = 16;
 impl Rel {
-    pub fn parse(i: &[u8]) -> IResult<&[u8], Self> {
-        let (i, typ) = number::le_u16(i)?;
-        let (i, addend) = number::le_i16(i)?;
-        let (i, needed) = number::le_u32(i)?;
-        let (i, offset) = number::le_u64(i)?;
+    pub fn parse(i: &[u8]) -> Result<(&[u8], Self), VsbfError> {
+        let (i, (typ, addend, needed, offset)) =
+            Self::parse_fields(i).map_err(truncated("relocation"))?;
+
+        let typ = RelocationType::try_from(typ)
+            .map_err(|typ| VsbfError::UnknownRelocationType { offset: 0, typ })?;
 
         let ret = Self {
             typ,
@@ -146,6 +713,15 @@ impl Rel {
         Ok((i, ret))
     }
 
+    fn parse_fields(i: &[u8]) -> IResult<&[u8], (u16, i16, u32, u64)> {
+        let (i, typ) = number::le_u16(i)?;
+        let (i, addend) = number::le_i16(i)?;
+        let (i, needed) = number::le_u32(i)?;
+        let (i, offset) = number::le_u64(i)?;
+
+        Ok((i, (typ, addend, needed, offset)))
+    }
+
     pub fn write(&self, w: &mut dyn io::Write) -> io::Result<()> {
         w.write_all(&self.typ.to_le_bytes())?;
         w.write_all(&self.addend.to_le_bytes())?;
@@ -157,21 +733,20 @@ impl Rel {
     pub fn print(obj: &Vsbf, rels: &[Self]) {
         let mut name_len = 4;
         for rel in rels {
-            name_len = 1 + obj.string_at(rel.needed).len().max(name_len);
+            name_len = 1 + obj.display_string_at(rel.needed).len().max(name_len);
         }
 
         println!(
-            "{:<name_len$} {:6} {:8} {}",
+            "{:<name_len$} {:8} {:10} Addend",
             "Name",
             "Type",
             "Offset",
-            "Addend",
             name_len = name_len,
         );
         for rel in rels {
             println!(
-                "{:<name_len$} 0x{:4x} 0x{:08x} {}",
-                obj.string_at(rel.needed),
+                "{:<name_len$} {:8} 0x{:08x} {}",
+                obj.display_string_at(rel.needed),
                 rel.typ,
                 rel.offset,
                 rel.addend,
@@ -181,6 +756,99 @@ impl Rel {
     }
 }
 
+/// The kind of patch a relocation asks the linker to perform.
+///
+/// Numbering follows the x86-64 System V psABI. In the formulas, `S` is the
+/// address of the symbol, `A` the addend, `P` the address of the place being
+/// patched, `L` the address of the symbol's PLT entry, `G` the offset of the
+/// symbol's entry in the GOT and `GOT` the address of the GOT itself.
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RelocationType {
+    /// `S + A`, 64 bits
+    Abs64 = 1,
+    /// `S + A - P`, 32 bits, sign-extended
+    Pc32 = 2,
+    /// `L + A - P`, 32 bits, sign-extended
+    Plt32 = 4,
+    /// `G + GOT + A - P`, 32 bits, sign-extended
+    GotPcRel = 9,
+    /// `S + A`, 32 bits, zero-extended
+    Abs32 = 10,
+    /// `S + A`, 32 bits, sign-extended
+    Abs32S = 11,
+}
+impl TryFrom<u16> for RelocationType {
+    type Error = u16;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        use RelocationType::*;
+        match value {
+            1 => Ok(Abs64),
+            2 => Ok(Pc32),
+            4 => Ok(Plt32),
+            9 => Ok(GotPcRel),
+            10 => Ok(Abs32),
+            11 => Ok(Abs32S),
+            _ => Err(value),
+        }
+    }
+}
+impl fmt::Display for RelocationType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            RelocationType::Abs64 => "abs64",
+            RelocationType::Pc32 => "pc32",
+            RelocationType::Plt32 => "plt32",
+            RelocationType::GotPcRel => "gotpcrel",
+            RelocationType::Abs32 => "abs32",
+            RelocationType::Abs32S => "abs32s",
+        })
+    }
+}
+impl RelocationType {
+    pub fn to_le_bytes(&self) -> [u8; 2] {
+        (*self as u16).to_le_bytes()
+    }
+
+    /// Number of bytes patched at the relocation's offset
+    pub fn width(&self) -> usize {
+        match self {
+            RelocationType::Abs64 => 8,
+            _ => 4,
+        }
+    }
+
+    /// Whether `P` is subtracted from the computed value
+    pub fn is_pc_relative(&self) -> bool {
+        use RelocationType::*;
+        matches!(self, Pc32 | Plt32 | GotPcRel)
+    }
+
+    /// Whether the symbol needs an entry in the GOT
+    pub fn needs_got(&self) -> bool {
+        *self == RelocationType::GotPcRel
+    }
+
+    /// Whether `value` can be stored in the relocation's field without overflowing
+    pub fn fits(&self, value: i128) -> bool {
+        match self {
+            RelocationType::Abs64 => u64::try_from(value).is_ok() || i64::try_from(value).is_ok(),
+            RelocationType::Abs32 => u32::try_from(value).is_ok(),
+            _ => i32::try_from(value).is_ok(),
+        }
+    }
+
+    /// The little-endian bytes to patch in for `value`, or `None` if it overflows
+    pub fn encode(&self, value: i128) -> Option<Vec<u8>> {
+        if !self.fits(value) {
+            return None;
+        }
+
+        Some(value.to_le_bytes()[..self.width()].to_vec())
+    }
+}
+
 bitflags! {
     /// The access restrictions the given segment will have when loaded in memory
     #[repr(C)]
@@ -217,7 +885,11 @@ pub struct SegmentHeader {
     pub mem_size: u32,
 }
 impl SegmentHeader {
-    pub fn parse(i: &[u8]) -> IResult<&[u8], Self> {
+    pub fn parse(i: &[u8]) -> Result<(&[u8], Self), VsbfError> {
+        Self::parse_fields(i).map_err(truncated("segment header"))
+    }
+
+    fn parse_fields(i: &[u8]) -> IResult<&[u8], Self> {
         let (i, typ) = number::le_u8(i)?;
         let (i, flags) = number::le_u8(i)?;
         let (i, align) = number::le_u16(i)?;
@@ -279,27 +951,49 @@ impl SegmentHeader {
     }
 }
 
-pub const SECTION_HDR_SIZE: u32 = 16;
+/// String table offset of sections without a name, which are named after
+/// their type (see [`SectionType::default_name`])
+pub const NO_NAME: u32 = u32::MAX;
+
+pub const SECTION_HDR_SIZE: u32 = 24;
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct SectionHeader {
+    /// Offset of the section name in the string table, or [`NO_NAME`]
+    /// (since version 4, no name before)
+    pub name: u32,
     pub typ: SectionType,
     pub flags: PermissionFlags,
-    pub file_size: u16,
+    /// Size of the section (since version 5, 16 bits before). BSS sections
+    /// don't store any bytes in the file, so for them this is only the size
+    /// they occupy in memory.
+    pub file_size: u32,
     pub offset: u32,
     pub memory: u64,
 }
 impl SectionHeader {
-    pub fn parse(i: &[u8]) -> IResult<&[u8], SectionHeader> {
-        let (i, typ) = number::le_u8(i)?;
-        let (i, flags) = number::le_u8(i)?;
-        let (i, file_size) = number::le_u16(i)?;
-        let (i, offset) = number::le_u32(i)?;
-        let (i, memory) = number::le_u64(i)?;
+    /// Size of a section header in the given format version
+    pub fn size(version: u16) -> u32 {
+        match version {
+            0..=3 => 16,
+            4 => 20,
+            _ => SECTION_HDR_SIZE,
+        }
+    }
+
+    pub fn parse(i: &[u8], version: u16) -> Result<(&[u8], SectionHeader), VsbfError> {
+        let (i, (typ, flags, file_size, offset, memory)) =
+            Self::parse_fields(i, version).map_err(truncated("section header"))?;
+        let (i, name) = match version {
+            0..=3 => (i, NO_NAME),
+            _ => number::le_u32::<_, ()>(i).map_err(truncated("section header"))?,
+        };
 
         let flags = PermissionFlags::from_bits_truncate(flags);
-        let typ = SectionType::try_from(typ).unwrap();
+        let typ = SectionType::try_from(typ)
+            .map_err(|typ| VsbfError::UnknownSectionType { offset: 0, typ })?;
 
         let ret = SectionHeader {
+            name,
             typ,
             flags,
             offset,
@@ -310,25 +1004,60 @@ impl SectionHeader {
         Ok((i, ret))
     }
 
+    fn parse_fields(i: &[u8], version: u16) -> IResult<&[u8], (u8, u8, u32, u32, u64)> {
+        let (i, typ) = number::le_u8(i)?;
+        let (i, flags) = number::le_u8(i)?;
+        let (i, file_size) = match version {
+            0..=4 => number::le_u16(i).map(|(i, size)| (i, size as u32))?,
+            _ => {
+                // The 16-bit size used to be here
+                let (i, _reserved) = number::le_u16(i)?;
+                number::le_u32(i)?
+            }
+        };
+        let (i, offset) = number::le_u32(i)?;
+        let (i, memory) = number::le_u64(i)?;
+
+        Ok((i, (typ, flags, file_size, offset, memory)))
+    }
+
     pub fn write(&self, w: &mut dyn io::Write) -> io::Result<()> {
         w.write_all(&self.typ.to_le_bytes())?;
         w.write_all(&self.flags.bits().to_le_bytes())?;
+        w.write_all(&0u16.to_le_bytes())?;
         w.write_all(&self.file_size.to_le_bytes())?;
         w.write_all(&self.offset.to_le_bytes())?;
         w.write_all(&self.memory.to_le_bytes())?;
+        w.write_all(&self.name.to_le_bytes())?;
         Ok(())
     }
 
-    pub fn print(hd: &[SectionHeader]) {
+    pub fn print(obj: &Vsbf, hd: &[SectionHeader]) {
+        let mut name_len = 4;
+        for hd in hd {
+            name_len = obj.display_section_name(hd).len().max(name_len);
+        }
+
         println!(
-            "{:4} {:4} {:8} {:8} {}",
-            "Type", "Flag", "Offset", "Size", "Address"
+            "{:<name_len$} {:6} {:4} {:8} {:8} Address",
+            "Name",
+            "Type",
+            "Flag",
+            "Offset",
+            "Size",
+            name_len = name_len,
         );
 
         for hd in hd {
             println!(
-                "{:4} {:4} {:08x} {:08x} {:08x}",
-                hd.typ, hd.flags, hd.offset, hd.file_size, hd.memory,
+                "{:<name_len$} {:6} {:4} {:08x} {:08x} {:08x}",
+                obj.display_section_name(hd),
+                hd.typ,
+                hd.flags,
+                hd.offset,
+                hd.file_size,
+                hd.memory,
+                name_len = name_len,
             );
         }
     }
@@ -342,6 +1071,22 @@ impl SectionHeader {
     pub fn is_rodata(&self) -> bool {
         self.typ == SectionType::Rodata
     }
+    pub fn is_bss(&self) -> bool {
+        self.typ == SectionType::Bss
+    }
+
+    /// Number of bytes the section occupies in the file
+    pub fn size_in_file(&self) -> u32 {
+        if self.is_bss() {
+            0
+        } else {
+            self.file_size
+        }
+    }
+    /// Number of bytes the section occupies in memory
+    pub fn size_in_memory(&self) -> u32 {
+        self.file_size
+    }
 
     pub fn is_ronly(&self) -> bool {
         self.flags == PermissionFlags::R
@@ -360,6 +1105,8 @@ pub enum SectionType {
     Text = 0,
     Data = 1,
     Rodata = 2,
+    /// Zero-initialized data, which takes no space in the file
+    Bss = 3,
 }
 impl TryFrom<u8> for SectionType {
     type Error = u8;
@@ -370,6 +1117,7 @@ impl TryFrom<u8> for SectionType {
             0 => Ok(Text),
             1 => Ok(Data),
             2 => Ok(Rodata),
+            3 => Ok(Bss),
             _ => Err(value),
         }
     }
@@ -380,63 +1128,123 @@ impl fmt::Display for SectionType {
             SectionType::Text => "text",
             SectionType::Data => "data",
             SectionType::Rodata => "rodata",
+            SectionType::Bss => "bss",
         })
     }
 }
 impl SectionType {
     pub fn to_le_bytes(&self) -> [u8; 1] {
-        return [*self as u8];
+        [*self as u8]
+    }
+
+    /// Name of the sections of this type that don't have one
+    pub fn default_name(&self) -> &'static str {
+        match self {
+            SectionType::Text => ".text",
+            SectionType::Data => ".data",
+            SectionType::Rodata => ".rodata",
+            SectionType::Bss => ".bss",
+        }
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+/// Indexes over the symbol table, to look symbols up without a linear scan
+#[derive(Clone, Debug, Default)]
+struct SymbolIndex {
+    /// First definition of every name, or first reference if it has none
+    by_name: HashMap<String, usize>,
+    by_section: HashMap<u16, Vec<usize>>,
+    /// Address of every symbol defined in a section, sorted by address
+    by_address: Vec<(u64, usize)>,
+}
+
+/// A [`SymbolIndex`] built on first use. It's derived from the rest of the
+/// file, so it doesn't take part in comparisons.
+#[derive(Clone, Debug, Default)]
+struct SymbolCache(OnceLock<SymbolIndex>);
+impl PartialEq for SymbolCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+impl Eq for SymbolCache {}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Vsbf {
+    flags: FileFlags,
     arch: u16,
     os: u16,
+    entry: u64,
     segments: Vec<SegmentHeader>,
     sections: Vec<SectionHeader>,
     strtab: Vec<u8>,
     syms: Vec<Sym>,
     rels: Vec<Rel>,
     data: Vec<u8>,
+    /// Must be cleared whenever symbols, sections or strings change
+    index: SymbolCache,
 }
 impl Vsbf {
     pub fn empty() -> Self {
         Self {
+            flags: FileFlags::empty(),
             arch: 0,
             os: 0,
+            entry: 0,
             segments: vec![],
             sections: vec![],
             strtab: vec![],
             syms: vec![],
             rels: vec![],
             data: vec![],
+            index: SymbolCache::default(),
         }
     }
 
-    pub fn parse(i: &[u8]) -> IResult<&[u8], Self> {
-        let (i, header) = FileHeader::parse(i)?;
+    pub fn parse(file: &[u8]) -> Result<(&[u8], Self), VsbfError> {
+        let (i, header) = FileHeader::parse(file)?;
 
         let n_syms = header.num_symbols as usize;
         let n_segs = header.num_segments as usize;
         let n_rels = header.num_relocs as usize;
         let n_sects = header.num_sections as usize;
 
-        let (i, segments) = multi::count(SegmentHeader::parse, n_segs)(i)?;
-        let (i, sections) = multi::count(SectionHeader::parse, n_sects)(i)?;
-        let (i, strtab) = bytes::take(header.strtab_size)(i)?;
-        let (i, syms) = multi::count(Sym::parse, n_syms)(i)?;
-        let (i, rels) = multi::count(Rel::parse, n_rels)(i)?;
+        let (i, segments) = count(
+            file,
+            i,
+            "segment header",
+            SEGMENT_HDR_SIZE,
+            n_segs,
+            SegmentHeader::parse,
+        )?;
+        let (i, sections) = count(
+            file,
+            i,
+            "section header",
+            SectionHeader::size(header.version),
+            n_sects,
+            |i| SectionHeader::parse(i, header.version),
+        )?;
+        let (i, strtab) = bytes::take::<_, _, ()>(header.strtab_size)(i)
+            .map_err(truncated("string table"))
+            .map_err(|e| e.offset_by(file.len() - i.len()))?;
+        let (i, syms) = count(file, i, "symbol", Sym::size(header.version), n_syms, |i| {
+            Sym::parse(i, header.version)
+        })?;
+        let (i, rels) = count(file, i, "relocation", REL_SIZE, n_rels, Rel::parse)?;
 
         let file = Vsbf {
+            flags: header.flags,
             arch: header.arch,
             os: header.os,
+            entry: header.entry,
             segments,
             sections,
             strtab: strtab.to_vec(),
             syms,
             rels,
             data: i.to_vec(),
+            index: SymbolCache::default(),
         };
 
         Ok((i, file))
@@ -444,6 +1252,8 @@ impl Vsbf {
 
     pub fn write(&self, w: &mut dyn io::Write) -> io::Result<()> {
         let header = FileHeader {
+            version: FORMAT_VERSION,
+            flags: self.flags,
             arch: self.arch,
             os: self.os,
             num_segments: self.segments.len() as _,
@@ -451,6 +1261,7 @@ impl Vsbf {
             strtab_size: self.strtab.len() as _,
             num_symbols: self.syms.len() as _,
             num_relocs: self.rels.len() as _,
+            entry: self.entry,
             next_header: 0,
         };
 
@@ -473,6 +1284,22 @@ impl Vsbf {
         Ok(())
     }
 
+    pub fn flags(&self) -> FileFlags {
+        self.flags
+    }
+
+    pub fn set_flags(&mut self, flags: FileFlags) {
+        self.flags = flags;
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn set_entry(&mut self, entry: u64) {
+        self.entry = entry;
+    }
+
     pub fn add_segment(&mut self, seg: SegmentHeader) {
         self.segments.push(seg);
     }
@@ -486,11 +1313,30 @@ impl Vsbf {
     }
 
     pub fn push_section(&mut self, sec: SectionHeader) {
+        self.index.0.take();
         self.sections.push(sec);
     }
 
     pub fn sections(&self) -> Vec<SectionHeader> {
-        return self.sections.clone();
+        self.sections.clone()
+    }
+
+    pub fn sections_mut(&mut self) -> &mut [SectionHeader] {
+        self.index.0.take();
+        &mut self.sections
+    }
+
+    /// Name of a section, or the default name for its type if it has none
+    pub fn section_name(&self, sec: &SectionHeader) -> Result<&str, VsbfError> {
+        match sec.name {
+            NO_NAME => Ok(sec.typ.default_name()),
+            name => self.string_at(name),
+        }
+    }
+
+    /// Like [`Vsbf::section_name`], but with a placeholder for invalid names
+    fn display_section_name(&self, sec: &SectionHeader) -> &str {
+        self.section_name(sec).unwrap_or("<invalid>")
     }
 
     pub fn data(&self) -> &[u8] {
@@ -504,34 +1350,34 @@ impl Vsbf {
     // === STRINGS ===
 
     pub fn set_strtab(&mut self, data: Vec<u8>) {
+        self.index.0.take();
         self.strtab = data;
     }
 
-    pub fn push_string(&mut self, data: &str) {
+    /// Append a string to the string table, returning its offset
+    pub fn push_string(&mut self, data: &str) -> u32 {
         assert!(data.is_ascii());
-        assert!(data.len() <= u16::MAX as usize);
-
-        let len = data.len() as u16;
-
-        self.strtab.extend_from_slice(&len.to_le_bytes());
-        self.strtab.extend_from_slice(data.as_bytes());
+        self.index.0.take();
+        push_string(&mut self.strtab, data)
     }
 
-    pub fn strings(&self) -> StrTabIter {
+    pub fn strings(&self) -> StrTabIter<'_> {
         StrTabIter(self, 0)
     }
 
-    pub fn string_at(&self, i: u32) -> &str {
-        let i = i as usize;
-        let len = u16::from_le_bytes(self.strtab[i..i + 2].try_into().unwrap()) as usize;
-        assert!(i + 2 + len <= self.strtab.len());
+    pub fn string_at(&self, offset: u32) -> Result<&str, VsbfError> {
+        string_at(&self.strtab, offset)
+    }
 
-        str::from_utf8(&self.strtab[i + 2..i + len + 2]).unwrap()
+    /// Like [`Vsbf::string_at`], but with a placeholder for invalid strings
+    fn display_string_at(&self, offset: u32) -> &str {
+        self.string_at(offset).unwrap_or("<invalid>")
     }
 
     // === SYMBOLS ===
 
     pub fn push_sym(&mut self, sym: Sym) {
+        self.index.0.take();
         self.syms.push(sym);
     }
 
@@ -540,11 +1386,69 @@ impl Vsbf {
     }
 
     pub fn syms_mut(&mut self) -> &mut [Sym] {
+        self.index.0.take();
         &mut self.syms
     }
 
+    fn symbol_index(&self) -> &SymbolIndex {
+        self.index.0.get_or_init(|| {
+            let mut index = SymbolIndex::default();
+
+            for (i, sym) in self.syms.iter().enumerate() {
+                if let Ok(name) = self.string_at(sym.name) {
+                    let first = index.by_name.entry(name.to_string()).or_insert(i);
+                    if self.syms[*first].is_undefined() && !sym.is_undefined() {
+                        *first = i;
+                    }
+                }
+
+                index.by_section.entry(sym.section).or_default().push(i);
+
+                if let Some(sec) = self.sections.get(sym.section as usize) {
+                    index
+                        .by_address
+                        .push((sec.memory.wrapping_add(sym.value), i));
+                }
+            }
+            index.by_address.sort_unstable();
+
+            index
+        })
+    }
+
+    /// Find a symbol by name, preferring a definition to references
+    pub fn find_symbol(&self, name: &str) -> Option<&Sym> {
+        let i = *self.symbol_index().by_name.get(name)?;
+        Some(&self.syms[i])
+    }
+
+    /// Every symbol in a section (or with one of the reserved section indices),
+    /// in symbol table order
+    pub fn symbols_in_section(&self, section: u16) -> impl Iterator<Item = &Sym> {
+        let indices = self.symbol_index().by_section.get(&section);
+        indices.into_iter().flatten().map(|&i| &self.syms[i])
+    }
+
+    /// Find the symbol defined at `addr`, that is the closest symbol starting
+    /// before `addr` and extending past it, with `addr`'s offset from its
+    /// start. Zero-sized symbols only match their own address.
+    pub fn symbol_at_address(&self, addr: u64) -> Option<(&Sym, u64)> {
+        let by_address = &self.symbol_index().by_address;
+        let end = by_address.partition_point(|&(start, _)| start <= addr);
+
+        by_address[..end].iter().rev().find_map(|&(start, i)| {
+            let sym = &self.syms[i];
+            let offset = addr - start;
+            (offset < sym.size.max(1) as u64).then_some((sym, offset))
+        })
+    }
+
     // === RELOCATIONS ===
 
+    pub fn push_rel(&mut self, rel: Rel) {
+        self.rels.push(rel);
+    }
+
     pub fn rels(&self) -> &[Rel] {
         &self.rels
     }
@@ -552,11 +1456,90 @@ impl Vsbf {
     pub fn rels_mut(&mut self) -> &mut [Rel] {
         &mut self.rels
     }
+
+    // === VALIDATION ===
+
+    /// Check that every header, symbol and relocation is consistent with the
+    /// rest of the file, returning every problem found.
+    pub fn validate(&self) -> Vec<Violation> {
+        use Violation::*;
+
+        let mut ret = vec![];
+        let data_len = self.data.len() as u64;
+
+        for (section, sec) in self.sections.iter().enumerate() {
+            let end = sec.offset as u64 + sec.size_in_file() as u64;
+            if end > data_len {
+                ret.push(SectionOutOfBounds { section, end });
+            }
+            if let Err(error) = self.section_name(sec) {
+                ret.push(BadSectionName { section, error });
+            }
+        }
+
+        for (segment, seg) in self.segments.iter().enumerate() {
+            let end = seg.file as u64 + seg.file_size as u64;
+            if end > data_len {
+                ret.push(SegmentOutOfBounds { segment, end });
+            }
+        }
+
+        for (first, a) in self.segments.iter().enumerate() {
+            for (second, b) in self.segments.iter().enumerate().skip(first + 1) {
+                let a_end = a.mem.saturating_add(a.mem_size as u64);
+                let b_end = b.mem.saturating_add(b.mem_size as u64);
+                if a.mem < b_end && b.mem < a_end {
+                    ret.push(SegmentsOverlap { first, second });
+                }
+            }
+        }
+
+        for (symbol, sym) in self.syms.iter().enumerate() {
+            if let Err(error) = self.string_at(sym.name) {
+                ret.push(BadSymbolName { symbol, error });
+            }
+
+            let section = sym.section;
+            if sym.is_undefined() || sym.is_absolute() || sym.is_common() {
+                continue;
+            }
+            match self.sections.get(section as usize) {
+                None => ret.push(NoSuchSection { symbol, section }),
+                Some(sec)
+                    if sym.value.saturating_add(sym.size as u64) > sec.size_in_memory() as u64 =>
+                {
+                    ret.push(SymbolOutOfSection { symbol, section })
+                }
+                Some(_) => {}
+            }
+        }
+
+        for (relocation, rel) in self.rels.iter().enumerate() {
+            if let Err(error) = self.string_at(rel.needed) {
+                ret.push(BadRelocationName { relocation, error });
+            }
+
+            let start = rel.offset;
+            let end = start.saturating_add(rel.typ.width() as u64);
+            let inside = self.sections.iter().any(|sec| {
+                let sec_start = sec.offset as u64;
+                sec_start <= start && end <= sec_start + sec.size_in_file() as u64
+            });
+            if !inside {
+                ret.push(RelocationOutOfSection {
+                    relocation,
+                    offset: rel.offset,
+                });
+            }
+        }
+
+        ret
+    }
 }
 
 pub struct StrTabIter<'a>(&'a Vsbf, usize);
 impl<'a> Iterator for StrTabIter<'a> {
-    type Item = (u32, &'a str);
+    type Item = Result<(u32, &'a str), VsbfError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let i = self.1;
@@ -565,14 +1548,278 @@ impl<'a> Iterator for StrTabIter<'a> {
             return None;
         }
 
-        let len = u16::from_le_bytes(self.0.strtab[i..i + 2].try_into().unwrap()) as usize;
-        assert!(i + 2 + len <= self.0.strtab.len());
+        match self.0.string_at(i as u32) {
+            Ok(str) => {
+                self.1 += 2 + str.len();
+                Some(Ok((i as u32, str)))
+            }
+            Err(e) => {
+                // A malformed string means we can't find where the next one starts
+                self.1 = self.0.strtab.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
 
-        self.1 += 2 + len;
-        Some((
-            i as u32,
-            str::from_utf8(&self.0.strtab[i + 2..i + 2 + len]).unwrap(),
-        ))
+// === ARCHIVES ===
+//
+// An archive bundles many objects into a single file, with an index of the
+// global symbols each of them defines:
+// - a header ([`ArchiveHeader`]);
+// - the member table: name, offset and size of every member;
+// - the symbol index: name of every symbol, and the member defining it;
+// - the string table holding member and symbol names;
+// - the members themselves, each a complete VSBF file.
+
+/// Magic of VSBF archives
+pub const ARCHIVE_MAGIC: [u8; 4] = *b"VSBA";
+/// Archive format version written by this crate
+pub const ARCHIVE_VERSION: u16 = 0;
+
+pub const ARCHIVE_HDR_SIZE: u32 = 20;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArchiveHeader {
+    pub version: u16,
+    pub num_members: u32,
+    pub num_symbols: u32,
+    pub strtab_size: u32,
+}
+impl ArchiveHeader {
+    pub fn parse(file: &[u8]) -> Result<(&[u8], Self), VsbfError> {
+        let (i, magic) =
+            bytes::take::<_, _, ()>(4usize)(file).map_err(truncated("archive header"))?;
+        if magic != ARCHIVE_MAGIC {
+            return Err(VsbfError::BadMagic);
+        }
+
+        let (i, ret) = Self::parse_fields(i).map_err(truncated("archive header"))?;
+        if ret.version > ARCHIVE_VERSION {
+            return Err(VsbfError::UnsupportedVersion(ret.version));
+        }
+
+        Ok((i, ret))
+    }
+
+    fn parse_fields(i: &[u8]) -> IResult<&[u8], Self> {
+        let (i, version) = number::le_u16(i)?;
+        let (i, _reserved) = number::le_u16(i)?;
+        let (i, num_members) = number::le_u32(i)?;
+        let (i, num_symbols) = number::le_u32(i)?;
+        let (i, strtab_size) = number::le_u32(i)?;
+
+        let ret = Self {
+            version,
+            num_members,
+            num_symbols,
+            strtab_size,
+        };
+        Ok((i, ret))
+    }
+
+    pub fn write(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        w.write_all(&ARCHIVE_MAGIC)?;
+        w.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+        w.write_all(&0u16.to_le_bytes())?;
+        w.write_all(&self.num_members.to_le_bytes())?;
+        w.write_all(&self.num_symbols.to_le_bytes())?;
+        w.write_all(&self.strtab_size.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+const ARCHIVE_MEMBER_SIZE: u32 = 12;
+const ARCHIVE_SYMBOL_SIZE: u32 = 8;
+
+/// An object stored in an archive
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveMember {
+    pub name: String,
+    /// The whole VSBF file
+    pub data: Vec<u8>,
+}
+
+/// An entry of the archive's symbol index
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveSymbol {
+    pub name: String,
+    /// Index of the member defining the symbol
+    pub member: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Archive {
+    members: Vec<ArchiveMember>,
+    symbols: Vec<ArchiveSymbol>,
+}
+impl Archive {
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Whether `file` looks like an archive rather than an object
+    pub fn is_archive(file: &[u8]) -> bool {
+        file.starts_with(&ARCHIVE_MAGIC)
+    }
+
+    pub fn parse(file: &[u8]) -> Result<Self, VsbfError> {
+        let (i, header) = ArchiveHeader::parse(file)?;
+
+        let (i, members) = count(
+            file,
+            i,
+            "archive member",
+            ARCHIVE_MEMBER_SIZE,
+            header.num_members as usize,
+            |i| Self::parse_member(i).map_err(truncated("archive member")),
+        )?;
+        let symbols_start = file.len() - i.len();
+        let (i, symbols) = count(
+            file,
+            i,
+            "archive symbol",
+            ARCHIVE_SYMBOL_SIZE,
+            header.num_symbols as usize,
+            |i| Self::parse_symbol(i).map_err(truncated("archive symbol")),
+        )?;
+
+        let strtab = i
+            .get(..header.strtab_size as usize)
+            .ok_or(VsbfError::Truncated {
+                what: "string table",
+                offset: file.len() - i.len(),
+            })?;
+
+        let members = members
+            .into_iter()
+            .map(|(name, offset, size)| {
+                let data = file
+                    .get(offset as usize..offset as usize + size as usize)
+                    .ok_or(VsbfError::Truncated {
+                        what: "archive member",
+                        offset: offset as usize,
+                    })?;
+                Ok(ArchiveMember {
+                    name: string_at(strtab, name)?.to_string(),
+                    data: data.to_vec(),
+                })
+            })
+            .collect::<Result<_, VsbfError>>()?;
+        let symbols = symbols
+            .into_iter()
+            .enumerate()
+            .map(|(j, (name, member))| {
+                if member >= header.num_members {
+                    return Err(VsbfError::MemberOutOfRange {
+                        offset: symbols_start + j * ARCHIVE_SYMBOL_SIZE as usize,
+                        member,
+                    });
+                }
+                Ok(ArchiveSymbol {
+                    name: string_at(strtab, name)?.to_string(),
+                    member,
+                })
+            })
+            .collect::<Result<_, VsbfError>>()?;
+
+        Ok(Self { members, symbols })
+    }
+
+    /// Parse a member table entry: name, offset and size
+    fn parse_member(i: &[u8]) -> IResult<&[u8], (u32, u32, u32)> {
+        let (i, name) = number::le_u32(i)?;
+        let (i, offset) = number::le_u32(i)?;
+        let (i, size) = number::le_u32(i)?;
+        Ok((i, (name, offset, size)))
+    }
+
+    /// Parse a symbol index entry: name and member
+    fn parse_symbol(i: &[u8]) -> IResult<&[u8], (u32, u32)> {
+        let (i, name) = number::le_u32(i)?;
+        let (i, member) = number::le_u32(i)?;
+        Ok((i, (name, member)))
+    }
+
+    pub fn write(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        let mut strtab = vec![];
+        let names: Vec<_> = self
+            .members
+            .iter()
+            .map(|m| push_string(&mut strtab, &m.name))
+            .collect();
+        let sym_names: Vec<_> = self
+            .symbols
+            .iter()
+            .map(|s| push_string(&mut strtab, &s.name))
+            .collect();
+
+        let header = ArchiveHeader {
+            version: ARCHIVE_VERSION,
+            num_members: self.members.len() as u32,
+            num_symbols: self.symbols.len() as u32,
+            strtab_size: strtab.len() as u32,
+        };
+        header.write(w)?;
+
+        let mut offset = ARCHIVE_HDR_SIZE
+            + ARCHIVE_MEMBER_SIZE * header.num_members
+            + ARCHIVE_SYMBOL_SIZE * header.num_symbols
+            + header.strtab_size;
+        for (member, name) in self.members.iter().zip(names) {
+            w.write_all(&name.to_le_bytes())?;
+            w.write_all(&offset.to_le_bytes())?;
+            w.write_all(&(member.data.len() as u32).to_le_bytes())?;
+            offset += member.data.len() as u32;
+        }
+        for (sym, name) in self.symbols.iter().zip(sym_names) {
+            w.write_all(&name.to_le_bytes())?;
+            w.write_all(&sym.member.to_le_bytes())?;
+        }
+
+        w.write_all(&strtab)?;
+        for member in &self.members {
+            w.write_all(&member.data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add an object to the archive, indexing the global symbols it defines
+    pub fn push_member(&mut self, name: &str, data: Vec<u8>) -> Result<(), VsbfError> {
+        let (_, obj) = Vsbf::parse(&data)?;
+        let member = self.members.len() as u32;
+
+        for sym in obj.syms() {
+            if sym.is_undefined() || sym.is_local() || sym.kind == SymbolKind::File {
+                continue;
+            }
+            self.symbols.push(ArchiveSymbol {
+                name: obj.string_at(sym.name)?.to_string(),
+                member,
+            });
+        }
+
+        self.members.push(ArchiveMember {
+            name: name.to_string(),
+            data,
+        });
+        Ok(())
+    }
+
+    pub fn members(&self) -> &[ArchiveMember] {
+        &self.members
+    }
+
+    pub fn symbols(&self) -> &[ArchiveSymbol] {
+        &self.symbols
+    }
+
+    /// Index of the first member defining the global symbol `name`
+    pub fn member_defining(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|sym| sym.name == name)
+            .map(|sym| sym.member)
     }
 }
 
@@ -586,31 +1833,65 @@ fn test_strtab() {
 
     let mut iter = vsbf.strings();
 
-    assert_eq!(iter.next().unwrap(), (0, "hello"));
-    assert_eq!(iter.next().unwrap(), (7, ""));
-    assert_eq!(iter.next().unwrap(), (9, "hi"));
+    assert_eq!(iter.next().unwrap(), Ok((0, "hello")));
+    assert_eq!(iter.next().unwrap(), Ok((7, "")));
+    assert_eq!(iter.next().unwrap(), Ok((9, "hi")));
     assert!(iter.next().is_none());
 
-    assert_eq!(vsbf.string_at(0), "hello");
-    assert_eq!(vsbf.string_at(7), "");
-    assert_eq!(vsbf.string_at(9), "hi");
+    assert_eq!(vsbf.string_at(0), Ok("hello"));
+    assert_eq!(vsbf.string_at(7), Ok(""));
+    assert_eq!(vsbf.string_at(9), Ok("hi"));
+    assert_eq!(
+        vsbf.string_at(12),
+        Err(VsbfError::StringOutOfRange { offset: 12 })
+    );
+}
+
+#[test]
+#[cfg(test)]
+fn test_strtab_builder() {
+    let mut strtab = StrTabBuilder::new();
+    assert!(strtab.is_empty());
+
+    assert_eq!(strtab.add("hello"), 0);
+    assert_eq!(strtab.add("Hi"), 7);
+    assert_eq!(strtab.add("hello"), 0);
+    assert_eq!(strtab.add(""), 11);
+    assert_eq!(strtab.add("Hi"), 7);
+    assert_eq!(strtab.len(), 13);
+
+    let mut vsbf = Vsbf::empty();
+    vsbf.set_strtab(strtab.finish());
+    let strings: Vec<_> = vsbf.strings().map(Result::unwrap).collect();
+    assert_eq!(strings, [(0, "hello"), (7, "Hi"), (11, "")]);
 }
 
 #[test]
 #[cfg(test)]
-#[should_panic]
 fn test_strtab_malformed() {
     let vsbf = Vsbf {
+        flags: FileFlags::empty(),
         arch: 0,
         os: 0,
+        entry: 0,
         segments: vec![],
         sections: vec![],
         strtab: vec![0x05, 0x00],
         data: vec![],
         rels: vec![],
         syms: vec![],
+        index: SymbolCache::default(),
     };
-    vsbf.strings().next();
+    let mut iter = vsbf.strings();
+    assert_eq!(
+        iter.next(),
+        Some(Err(VsbfError::StringOutOfRange { offset: 0 }))
+    );
+    assert_eq!(iter.next(), None);
+
+    let mut vsbf = Vsbf::empty();
+    vsbf.set_strtab(vec![0x02, 0x00, 0xc3, 0x28]);
+    assert_eq!(vsbf.string_at(0), Err(VsbfError::InvalidUtf8 { offset: 0 }));
 }
 
 #[test]
@@ -633,7 +1914,580 @@ fn test_parse_write() {
         size: 10,
         section: 0,
         value: 20,
+        binding: SymbolBinding::Weak,
+        kind: SymbolKind::Func,
     });
     vsbf.write(&mut buf).unwrap();
     assert_eq!(Vsbf::parse(&buf.into_inner()).unwrap().1, vsbf);
+
+    // Symbols from before version 5 have a 16-bit size...
+    let sym = vsbf.syms()[0];
+    let mut old = vec![];
+    old.extend_from_slice(&sym.name.to_le_bytes());
+    old.extend_from_slice(&(sym.size as u16).to_le_bytes());
+    old.extend_from_slice(&sym.section.to_le_bytes());
+    old.extend_from_slice(&sym.value.to_le_bytes());
+    old.extend_from_slice(&[SymbolBinding::Weak as u8, SymbolKind::Func as u8]);
+    assert_eq!(Sym::parse(&old, 4).unwrap(), (&[][..], sym));
+
+    // ...and from before version 3 no binding nor kind
+    let (rest, v2) = Sym::parse(&old, 2).unwrap();
+    assert_eq!(rest, [SymbolBinding::Weak as u8, SymbolKind::Func as u8]);
+    assert_eq!(v2.binding, SymbolBinding::Global);
+    assert_eq!(v2.kind, SymbolKind::None);
+    assert_eq!(
+        Sym::parse(&[&old[..16], &[7, 0]].concat(), 3).unwrap_err(),
+        VsbfError::UnknownSymbolBinding {
+            offset: 0,
+            binding: 7
+        }
+    );
+
+    // File with a relocation
+    let mut buf = Cursor::new(vec![]);
+    let mut vsbf = Vsbf::empty();
+    vsbf.push_string("hello");
+    vsbf.push_rel(Rel {
+        typ: RelocationType::Plt32,
+        addend: -4,
+        needed: 0,
+        offset: 1,
+    });
+    vsbf.write(&mut buf).unwrap();
+    assert_eq!(Vsbf::parse(&buf.into_inner()).unwrap().1, vsbf);
+
+    // File with an entry point
+    let mut buf = Cursor::new(vec![]);
+    let mut vsbf = Vsbf::empty();
+    vsbf.set_entry(0x1234);
+    vsbf.write(&mut buf).unwrap();
+    let buf = buf.into_inner();
+    assert_eq!(buf.len(), FILE_HDR_SIZE as usize);
+    assert_eq!(Vsbf::parse(&buf).unwrap().1, vsbf);
+}
+
+#[test]
+#[cfg(test)]
+fn test_parse_v0() {
+    let mut buf = b"VSBF".to_vec();
+    buf.extend_from_slice(&[0; 4]); // arch, os
+    buf.extend_from_slice(&[0, 0, 1, 0]); // segments, sections
+    buf.extend_from_slice(&[2, 0, 0, 0]); // strtab size
+    buf.extend_from_slice(&[0; 8]); // symbols, relocations
+    buf.extend_from_slice(&[0; 8]); // next header
+    buf.extend_from_slice(&[0, 5, 4, 0, 0, 0, 0, 0]); // section header
+    buf.extend_from_slice(&[0; 8]);
+    buf.extend_from_slice(&[0, 0]); // strtab
+    buf.extend_from_slice(b"data");
+
+    let (_, hdr) = FileHeader::parse(&buf).unwrap();
+    assert_eq!(hdr.version, 0);
+    assert_eq!(hdr.entry, 0);
+
+    let (_, vsbf) = Vsbf::parse(&buf).unwrap();
+    assert_eq!(vsbf.entry(), 0);
+    assert_eq!(vsbf.flags(), FileFlags::empty());
+    assert_eq!(vsbf.sections()[0].file_size, 4);
+    assert_eq!(vsbf.string_at(0), Ok(""));
+    assert_eq!(vsbf.data(), b"data");
+}
+
+#[test]
+#[cfg(test)]
+fn test_relocation_type() {
+    use RelocationType::*;
+
+    for typ in [Abs64, Pc32, Plt32, GotPcRel, Abs32, Abs32S] {
+        assert_eq!(RelocationType::try_from(typ as u16), Ok(typ));
+    }
+    assert_eq!(RelocationType::try_from(3), Err(3));
+
+    assert_eq!(Abs64.encode(-1), Some(vec![0xff; 8]));
+    assert_eq!(Abs64.encode(u64::MAX as i128), Some(vec![0xff; 8]));
+    assert_eq!(Abs64.encode(u64::MAX as i128 + 1), None);
+
+    assert_eq!(Abs32.encode(u32::MAX as i128), Some(vec![0xff; 4]));
+    assert_eq!(Abs32.encode(-1), None);
+
+    assert_eq!(Abs32S.encode(-1), Some(vec![0xff; 4]));
+    assert_eq!(Abs32S.encode(u32::MAX as i128), None);
+
+    assert_eq!(Pc32.encode(-8), Some((-8i32).to_le_bytes().to_vec()));
+    assert_eq!(Pc32.encode(i32::MIN as i128 - 1), None);
+}
+
+#[test]
+#[cfg(test)]
+fn test_parse_errors() {
+    use std::io::Cursor;
+
+    assert_eq!(Vsbf::parse(b"ELF\x7f").unwrap_err(), VsbfError::BadMagic);
+    assert_eq!(
+        Vsbf::parse(b"VSBV\xff\xff").unwrap_err(),
+        VsbfError::UnsupportedVersion(0xffff)
+    );
+    assert_eq!(
+        Vsbf::parse(b"VSBV\x01\x00\x00\x00").unwrap_err(),
+        VsbfError::Truncated {
+            what: "file header",
+            offset: 0
+        }
+    );
+
+    let mut vsbf = Vsbf::empty();
+    vsbf.push_section(SectionHeader {
+        name: NO_NAME,
+        typ: SectionType::Text,
+        flags: PermissionFlags::R,
+        file_size: 0,
+        offset: 0,
+        memory: 0,
+    });
+    let mut buf = Cursor::new(vec![]);
+    vsbf.write(&mut buf).unwrap();
+    let mut buf = buf.into_inner();
+
+    // Section header cut in half
+    assert_eq!(
+        Vsbf::parse(&buf[..buf.len() - 8]).unwrap_err(),
+        VsbfError::CountOverflow {
+            what: "section header",
+            count: 1
+        }
+    );
+
+    // Section header with a bogus type, right after the file header
+    buf[FILE_HDR_SIZE as usize] = 0xff;
+    assert_eq!(
+        Vsbf::parse(&buf).unwrap_err(),
+        VsbfError::UnknownSectionType {
+            offset: FILE_HDR_SIZE as usize,
+            typ: 0xff
+        }
+    );
+
+    // Header claiming more symbols than could possibly fit
+    let mut buf = Cursor::new(vec![]);
+    Vsbf::empty().write(&mut buf).unwrap();
+    let mut buf = buf.into_inner();
+    buf[22..26].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(
+        Vsbf::parse(&buf).unwrap_err(),
+        VsbfError::CountOverflow {
+            what: "symbol",
+            count: u32::MAX as u64
+        }
+    );
+}
+
+#[test]
+#[cfg(test)]
+fn test_validate() {
+    let mut vsbf = Vsbf::empty();
+    vsbf.push_string("sym");
+    vsbf.data_mut().extend_from_slice(&[0; 16]);
+    vsbf.push_section(SectionHeader {
+        name: NO_NAME,
+        typ: SectionType::Text,
+        flags: PermissionFlags::R | PermissionFlags::X,
+        file_size: 16,
+        offset: 0,
+        memory: 0,
+    });
+    vsbf.push_segment(SegmentHeader {
+        typ: 0,
+        flags: PermissionFlags::R | PermissionFlags::X,
+        align: 0x1000,
+        file: 0,
+        mem: 0x1000,
+        file_size: 16,
+        mem_size: 16,
+    });
+    vsbf.push_sym(Sym {
+        name: 0,
+        size: 4,
+        section: 0,
+        value: 12,
+        binding: SymbolBinding::Global,
+        kind: SymbolKind::None,
+    });
+    vsbf.push_rel(Rel {
+        typ: RelocationType::Pc32,
+        addend: -4,
+        needed: 0,
+        offset: 12,
+    });
+    for section in [SECTION_UNDEF, SECTION_ABS, SECTION_COMMON] {
+        vsbf.push_sym(Sym {
+            name: 0,
+            size: 4,
+            section,
+            value: 0x1000,
+            binding: SymbolBinding::Global,
+            kind: SymbolKind::None,
+        });
+    }
+    assert_eq!(vsbf.validate(), []);
+
+    vsbf.push_section(SectionHeader {
+        name: NO_NAME,
+        typ: SectionType::Data,
+        flags: PermissionFlags::R | PermissionFlags::W,
+        file_size: 8,
+        offset: 12,
+        memory: 0,
+    });
+    vsbf.push_segment(SegmentHeader {
+        typ: 0,
+        flags: PermissionFlags::R | PermissionFlags::W,
+        align: 0x1000,
+        file: 12,
+        mem: 0x1008,
+        file_size: 8,
+        mem_size: 8,
+    });
+    vsbf.push_sym(Sym {
+        name: 0,
+        size: 4,
+        section: 0,
+        value: 14,
+        binding: SymbolBinding::Global,
+        kind: SymbolKind::None,
+    });
+    vsbf.push_sym(Sym {
+        name: 100,
+        size: 0,
+        section: 2,
+        value: 0,
+        binding: SymbolBinding::Global,
+        kind: SymbolKind::None,
+    });
+    vsbf.push_rel(Rel {
+        typ: RelocationType::Abs64,
+        addend: 0,
+        needed: 0,
+        offset: 10,
+    });
+
+    use Violation::*;
+    assert_eq!(
+        vsbf.validate(),
+        [
+            SectionOutOfBounds {
+                section: 1,
+                end: 20
+            },
+            SegmentOutOfBounds {
+                segment: 1,
+                end: 20
+            },
+            SegmentsOverlap {
+                first: 0,
+                second: 1
+            },
+            SymbolOutOfSection {
+                symbol: 4,
+                section: 0
+            },
+            BadSymbolName {
+                symbol: 5,
+                error: VsbfError::StringOutOfRange { offset: 100 }
+            },
+            NoSuchSection {
+                symbol: 5,
+                section: 2
+            },
+            RelocationOutOfSection {
+                relocation: 1,
+                offset: 10
+            },
+        ]
+    );
+}
+
+#[test]
+#[cfg(test)]
+fn test_parse_versions() {
+    use std::io::Cursor;
+
+    let mut vsbf = Vsbf::empty();
+    vsbf.set_entry(0x1234);
+    vsbf.set_flags(FileFlags::EXEC);
+    vsbf.data_mut().extend_from_slice(b"data");
+    let mut buf = Cursor::new(vec![]);
+    vsbf.write(&mut buf).unwrap();
+    let buf = buf.into_inner();
+    let (header, rest) = buf.split_at(FILE_HDR_SIZE as usize);
+
+    // Version 1: no header size nor flags
+    let mut v1 = b"VSBV\x01\x00".to_vec();
+    v1.extend_from_slice(&header[10..]);
+    v1.extend_from_slice(rest);
+    let (_, hdr) = FileHeader::parse(&v1).unwrap();
+    assert_eq!(hdr.version, 1);
+    assert_eq!(hdr.flags, FileFlags::empty());
+    assert_eq!(hdr.entry, 0x1234);
+    assert_eq!(Vsbf::parse(&v1).unwrap().1.data(), b"data");
+
+    // Unknown optional header fields and flags are skipped
+    let mut v2 = header.to_vec();
+    v2[6..8].copy_from_slice(&(FILE_HDR_SIZE as u16 + 3).to_le_bytes());
+    v2[8..10].copy_from_slice(&0x0081u16.to_le_bytes());
+    v2.extend_from_slice(b"new");
+    v2.extend_from_slice(rest);
+    let (_, parsed) = Vsbf::parse(&v2).unwrap();
+    assert_eq!(parsed, vsbf);
+
+    // Unknown required flags are not
+    let mut v2 = buf.clone();
+    v2[8..10].copy_from_slice(&0x0101u16.to_le_bytes());
+    assert_eq!(
+        Vsbf::parse(&v2).unwrap_err(),
+        VsbfError::UnsupportedFlags(0x0100)
+    );
+
+    // Neither are newer versions
+    let mut newer = buf.clone();
+    newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+    assert_eq!(
+        Vsbf::parse(&newer).unwrap_err(),
+        VsbfError::UnsupportedVersion(FORMAT_VERSION + 1)
+    );
+}
+
+#[test]
+#[cfg(test)]
+fn test_validate_bss() {
+    let mut vsbf = Vsbf::empty();
+    vsbf.push_string("buf");
+    vsbf.push_section(SectionHeader {
+        name: NO_NAME,
+        typ: SectionType::Bss,
+        flags: PermissionFlags::R | PermissionFlags::W,
+        file_size: 0x100,
+        offset: 0,
+        memory: 0,
+    });
+    vsbf.push_sym(Sym {
+        name: 0,
+        size: 0x80,
+        section: 0,
+        value: 0x80,
+        binding: SymbolBinding::Global,
+        kind: SymbolKind::Object,
+    });
+    assert_eq!(vsbf.validate(), []);
+
+    // There are no bytes to relocate in a BSS section
+    vsbf.push_rel(Rel {
+        typ: RelocationType::Abs64,
+        addend: 0,
+        needed: 0,
+        offset: 0,
+    });
+    assert_eq!(
+        vsbf.validate(),
+        [Violation::RelocationOutOfSection {
+            relocation: 0,
+            offset: 0
+        }]
+    );
+}
+
+#[test]
+#[cfg(test)]
+fn test_archive() {
+    let mut foo = Vsbf::empty();
+    foo.push_string("foo");
+    foo.push_string("local");
+    foo.push_string("bar");
+    for (name, section, binding) in [
+        (0, 0, SymbolBinding::Global),
+        (5, 0, SymbolBinding::Local),
+        (12, SECTION_UNDEF, SymbolBinding::Global),
+    ] {
+        foo.push_sym(Sym {
+            name,
+            size: 0,
+            section,
+            value: 0,
+            binding,
+            kind: SymbolKind::Func,
+        });
+    }
+    foo.push_section(SectionHeader {
+        name: NO_NAME,
+        typ: SectionType::Text,
+        flags: PermissionFlags::R | PermissionFlags::X,
+        file_size: 1,
+        offset: 0,
+        memory: 0,
+    });
+    foo.data_mut().push(0xc3);
+    let mut foo_data = vec![];
+    foo.write(&mut foo_data).unwrap();
+
+    let mut ar = Archive::empty();
+    ar.push_member("empty.o", {
+        let mut data = vec![];
+        Vsbf::empty().write(&mut data).unwrap();
+        data
+    })
+    .unwrap();
+    ar.push_member("foo.o", foo_data.clone()).unwrap();
+    assert_eq!(
+        ar.push_member("bad.o", b"junk".to_vec()),
+        Err(VsbfError::BadMagic)
+    );
+
+    assert_eq!(ar.members().len(), 2);
+    assert_eq!(
+        ar.symbols(),
+        [ArchiveSymbol {
+            name: "foo".to_string(),
+            member: 1
+        }]
+    );
+    assert_eq!(ar.member_defining("foo"), Some(1));
+    assert_eq!(ar.member_defining("bar"), None);
+
+    let mut file = vec![];
+    ar.write(&mut file).unwrap();
+    assert!(Archive::is_archive(&file));
+    assert!(!Archive::is_archive(&foo_data));
+
+    let parsed = Archive::parse(&file).unwrap();
+    assert_eq!(parsed, ar);
+    assert_eq!(parsed.members()[1].data, foo_data);
+
+    assert!(matches!(
+        Archive::parse(&file[..file.len() - 1]),
+        Err(VsbfError::Truncated {
+            what: "archive member",
+            ..
+        })
+    ));
+    assert_eq!(Archive::parse(&foo_data), Err(VsbfError::BadMagic));
+
+    // The symbol index comes right after the 20-byte header and 2 members
+    let mut bad_index = file.clone();
+    bad_index[48..52].copy_from_slice(&2u32.to_le_bytes());
+    assert_eq!(
+        Archive::parse(&bad_index),
+        Err(VsbfError::MemberOutOfRange {
+            offset: 44,
+            member: 2
+        })
+    );
+}
+
+#[test]
+#[cfg(test)]
+fn test_section_names() {
+    let mut vsbf = Vsbf::empty();
+    let name = vsbf.push_string(".text.main");
+    let mut sec = SectionHeader {
+        name,
+        typ: SectionType::Text,
+        flags: PermissionFlags::R | PermissionFlags::X,
+        file_size: 0,
+        offset: 0,
+        memory: 0,
+    };
+    assert_eq!(vsbf.section_name(&sec), Ok(".text.main"));
+
+    let mut buf = vec![];
+    sec.write(&mut buf).unwrap();
+    assert_eq!(buf.len(), SECTION_HDR_SIZE as usize);
+    assert_eq!(SectionHeader::parse(&buf, FORMAT_VERSION).unwrap().1, sec);
+
+    // Sections from before version 5 have a 16-bit size, and from before
+    // version 4 are named after their type
+    let v4 = [&buf[..2], &buf[4..6], &buf[8..]].concat();
+    assert_eq!(SectionHeader::parse(&v4, 4).unwrap(), (&[][..], sec));
+    let (rest, old) = SectionHeader::parse(&v4, 3).unwrap();
+    assert_eq!(rest.len(), 4);
+    assert_eq!(old.name, NO_NAME);
+    assert_eq!(vsbf.section_name(&old), Ok(".text"));
+
+    sec.name = 0x100;
+    vsbf.push_section(sec);
+    assert_eq!(
+        vsbf.validate(),
+        [Violation::BadSectionName {
+            section: 0,
+            error: VsbfError::StringOutOfRange { offset: 0x100 }
+        }]
+    );
+}
+
+#[test]
+#[cfg(test)]
+fn test_symbol_lookup() {
+    let mut vsbf = Vsbf::empty();
+    for memory in [0x1000, 0x2000] {
+        vsbf.push_section(SectionHeader {
+            name: NO_NAME,
+            typ: SectionType::Text,
+            flags: PermissionFlags::R | PermissionFlags::X,
+            file_size: 0x100,
+            offset: 0,
+            memory,
+        });
+    }
+    let symbols = [
+        ("printf", SECTION_UNDEF, 0, 0),
+        ("main", 0, 0x10, 0x20),
+        ("helper", 0, 0x30, 0),
+        ("printf", 1, 0, 0x40),
+        ("inner", 1, 0x8, 0x8),
+        ("answer", SECTION_ABS, 0x1010, 0),
+    ];
+    for (name, section, value, size) in symbols {
+        let name = vsbf.push_string(name);
+        vsbf.push_sym(Sym {
+            name,
+            size,
+            section,
+            value,
+            binding: SymbolBinding::Global,
+            kind: SymbolKind::Func,
+        });
+    }
+    let syms = vsbf.syms().to_vec();
+
+    assert_eq!(vsbf.find_symbol("main"), Some(&syms[1]));
+    assert_eq!(vsbf.find_symbol("printf"), Some(&syms[3]));
+    assert_eq!(vsbf.find_symbol("nope"), None);
+
+    let in_section: Vec<_> = vsbf.symbols_in_section(1).collect();
+    assert_eq!(in_section, [&syms[3], &syms[4]]);
+    assert_eq!(vsbf.symbols_in_section(SECTION_UNDEF).count(), 1);
+    assert_eq!(vsbf.symbols_in_section(7).count(), 0);
+
+    assert_eq!(vsbf.symbol_at_address(0x1010), Some((&syms[1], 0)));
+    assert_eq!(vsbf.symbol_at_address(0x102f), Some((&syms[1], 0x1f)));
+    assert_eq!(vsbf.symbol_at_address(0x1030), Some((&syms[2], 0)));
+    assert_eq!(vsbf.symbol_at_address(0x1031), None);
+    assert_eq!(vsbf.symbol_at_address(0x100f), None);
+    // The innermost symbol wins, then the enclosing one again
+    assert_eq!(vsbf.symbol_at_address(0x2009), Some((&syms[4], 1)));
+    assert_eq!(vsbf.symbol_at_address(0x2010), Some((&syms[3], 0x10)));
+
+    // Changes to the file are picked up
+    vsbf.sections_mut()[0].memory = 0x4000;
+    assert_eq!(vsbf.symbol_at_address(0x4010), Some((&syms[1], 0)));
+    vsbf.syms_mut()[1].value = 0;
+    assert_eq!(vsbf.symbol_at_address(0x4000).unwrap().0.value, 0);
+    let name = vsbf.push_string("late");
+    vsbf.syms_mut()[2].name = name;
+    assert_eq!(vsbf.find_symbol("late"), Some(&vsbf.syms()[2]));
+    assert_eq!(vsbf.find_symbol("helper"), None);
+
+    // The index doesn't affect comparisons
+    assert_eq!(vsbf.clone(), {
+        let mut fresh = vsbf.clone();
+        fresh.syms_mut();
+        fresh
+    });
 }
@@ -10,7 +10,13 @@ fn main() {
 
     let filename = args().nth(1).unwrap();
     let buf = std::fs::read(&filename).unwrap();
-    let (_, mut obj) = Vsbf::parse(&buf).unwrap();
+    let (_, mut obj) = match Vsbf::parse(&buf) {
+        Ok(obj) => obj,
+        Err(e) => {
+            eprintln!("error: {filename}: {e}");
+            exit(1);
+        }
+    };
 
     let sections = obj.sections();
 